@@ -1,15 +1,74 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::{Balance, EpochHeight};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, Balance, BlockHeight, EpochHeight};
 
 /// A type to distinguish between a balance and "stake" shares for better readability.
 pub type NumStakeShares = Balance;
 
+/// The cross-contract operation an account is waiting for
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PendingOperationKind {
+    DepositAndStake,
+    Unstake,
+    Withdraw,
+    /// busy flag migrated from v1.1.0, the operation in flight is not known
+    Unknown,
+    /// unstaked balance sent back to stake (`stake`, `stake_all`)
+    Restake,
+    /// deposited balance sent to stake (`stake`, `stake_all`)
+    Stake,
+}
+
+/// Record of the cross-contract call an account is involved in.
+/// While it's set the account is busy (avoids re-entry attacks)
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone)]
+pub struct PendingOperation {
+    pub kind: PendingOperationKind,
+    /// NEAR sent to Meta Pool (deposit, stake), requested from Meta Pool (withdraw) or restaked
+    pub amount: Balance,
+    /// shares sent to Meta Pool to unstake
+    pub shares: NumStakeShares,
+    /// when the operation started
+    pub block_height: BlockHeight,
+    pub epoch_height: EpochHeight,
+}
+
+impl PendingOperation {
+    pub fn new(kind: PendingOperationKind, amount: Balance, shares: NumStakeShares) -> Self {
+        Self {
+            kind,
+            amount,
+            shares,
+            block_height: env::block_height(),
+            epoch_height: env::epoch_height(),
+        }
+    }
+
+    /// true if this is the operation a callback expects to close
+    pub fn matches(&self, kind: PendingOperationKind, amount: Balance, shares: NumStakeShares) -> bool {
+        self.kind == kind && self.amount == amount && self.shares == shares
+    }
+}
+
+/// Max tranches per account, a new maturity epoch past it is merged into the latest tranche
+pub const MAX_UNSTAKE_TRANCHES: usize = 16;
+
+/// NEAR unstaked by one or more `unstake` calls, withdrawable from the same epoch
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone)]
+pub struct UnstakeTranche {
+    pub amount: Balance,
+    /// epoch returned by Meta Pool for the unstake
+    pub available_epoch_height: EpochHeight,
+}
+
 /// Inner account data of a delegate.
-#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Default)]
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Default, Clone)]
 pub struct Account {
-    /// The account is involved in a cross-contract call
-    /// avoids re-entry attacks
-    pub busy: bool,
+    /// The cross-contract call the account is involved in, if any.
+    /// The account is busy while it's set
+    pub pending_operation: Option<PendingOperation>,
     /// The unstaked balance in Meta Pool corresponding to this account.
     /// when a delay unstake is initiated, the same order is sent to Meta Pool,
     /// and we register here the amount it should be available there after 4 epochs
@@ -21,11 +80,208 @@ pub struct Account {
     pub stake_shares: NumStakeShares,
     /// The minimum epoch height when the withdrawn is allowed.
     /// This changes after unstaking action, because the amount is still locked for 3 epochs.
+    /// It's the latest maturity of `unstake_tranches`: the whole unstaked balance is available from it
     pub unstaked_available_epoch_height: EpochHeight,
+    /// `backend_generation` of the contract when the account started staking,
+    /// the account's shares are held at the previous Meta Pool contract if it's not the current one
+    pub backend_generation: u32,
+    /// `unstaked_in_metapool` split by maturity epoch, earliest first. They add up to `unstaked_in_metapool`
+    pub unstake_tranches: Vec<UnstakeTranche>,
+    /// NEAR received with `deposit` and held by this contract until it's staked or withdrawn
+    pub deposited_balance: Balance,
 }
 
 impl Account {
+    pub fn is_busy(&self) -> bool {
+        self.pending_operation.is_some()
+    }
+
     pub fn is_empty(&self) -> bool {
-        !self.busy && self.unstaked_in_metapool == 0 && self.stake_shares == 0
+        !self.has_backend_funds() && self.deposited_balance == 0
+    }
+
+    /// true if the account has (or may have, while busy) funds at the Meta Pool contract
+    pub fn has_backend_funds(&self) -> bool {
+        self.is_busy() || self.unstaked_in_metapool != 0 || self.stake_shares != 0
+    }
+
+    /// Unstaked balance of the tranches matured by `epoch_height`
+    pub fn matured_unstaked(&self, epoch_height: EpochHeight) -> Balance {
+        self.unstake_tranches
+            .iter()
+            .filter(|tranche| tranche.available_epoch_height <= epoch_height)
+            .map(|tranche| tranche.amount)
+            .sum()
+    }
+
+    /// Registers `amount` unstaked in Meta Pool, withdrawable from `available_epoch_height`
+    pub fn add_unstaked(&mut self, amount: Balance, available_epoch_height: EpochHeight) {
+        self.unstaked_in_metapool += amount;
+        self.unstaked_available_epoch_height =
+            std::cmp::max(self.unstaked_available_epoch_height, available_epoch_height);
+        let tranches = &mut self.unstake_tranches;
+        if let Some(tranche) = tranches
+            .iter_mut()
+            .find(|tranche| tranche.available_epoch_height == available_epoch_height)
+        {
+            tranche.amount += amount;
+        } else if tranches.len() >= MAX_UNSTAKE_TRANCHES {
+            // the latest tranche takes it, at the latest of both epochs
+            let last = tranches.last_mut().unwrap();
+            last.amount += amount;
+            last.available_epoch_height =
+                std::cmp::max(last.available_epoch_height, available_epoch_height);
+        } else {
+            let index = tranches
+                .iter()
+                .position(|tranche| tranche.available_epoch_height > available_epoch_height)
+                .unwrap_or(tranches.len());
+            tranches.insert(
+                index,
+                UnstakeTranche {
+                    amount,
+                    available_epoch_height,
+                },
+            );
+        }
+    }
+
+    /// Removes `amount` withdrawn from Meta Pool, consuming the earliest tranches first
+    pub fn remove_unstaked(&mut self, amount: Balance) {
+        self.unstaked_in_metapool = self.unstaked_in_metapool.saturating_sub(amount);
+        let mut left = amount;
+        while left > 0 && !self.unstake_tranches.is_empty() {
+            let first = &mut self.unstake_tranches[0];
+            if first.amount > left {
+                first.amount -= left;
+                left = 0;
+            } else {
+                left -= first.amount;
+                self.unstake_tranches.remove(0);
+            }
+        }
+    }
+
+    /// Removes `amount` staked again from the tranches matured by `epoch_height`, the latest ones first
+    pub fn remove_restaked(&mut self, amount: Balance, epoch_height: EpochHeight) {
+        self.unstaked_in_metapool = self.unstaked_in_metapool.saturating_sub(amount);
+        let mut left = amount;
+        for tranche in self
+            .unstake_tranches
+            .iter_mut()
+            .rev()
+            .filter(|tranche| tranche.available_epoch_height <= epoch_height)
+        {
+            let taken = std::cmp::min(left, tranche.amount);
+            tranche.amount -= taken;
+            left -= taken;
+            if left == 0 {
+                break;
+            }
+        }
+        self.unstake_tranches.retain(|tranche| tranche.amount > 0);
+        if let Some(last) = self.unstake_tranches.last() {
+            self.unstaked_available_epoch_height = last.available_epoch_height;
+        }
+    }
+}
+
+/// Account layout deployed as v1.1.0
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Default, Clone)]
+pub struct AccountV1 {
+    pub busy: bool,
+    pub unstaked_in_metapool: Balance,
+    pub stake_shares: NumStakeShares,
+    pub unstaked_available_epoch_height: EpochHeight,
+}
+
+impl From<AccountV1> for Account {
+    fn from(v1: AccountV1) -> Self {
+        Self {
+            pending_operation: if v1.busy {
+                Some(PendingOperation::new(PendingOperationKind::Unknown, 0, 0))
+            } else {
+                None
+            },
+            unstaked_in_metapool: v1.unstaked_in_metapool,
+            stake_shares: v1.stake_shares,
+            unstaked_available_epoch_height: v1.unstaked_available_epoch_height,
+            backend_generation: 0,
+            // the unstaked balance as a single tranche
+            unstake_tranches: if v1.unstaked_in_metapool == 0 {
+                vec![]
+            } else {
+                vec![UnstakeTranche {
+                    amount: v1.unstaked_in_metapool,
+                    available_epoch_height: v1.unstaked_available_epoch_height,
+                }]
+            },
+            deposited_balance: 0,
+        }
+    }
+}
+
+/// Stored form of an account in `accounts`. The v1.1.0 layout (`AccountV1`) is stored
+/// untagged in `legacy_accounts`, so only the current layout was ever written here.
+/// When `Account` changes, its current layout is frozen in a new struct that keeps
+/// its variant, and the new layout gets the next one, so old entries remain readable.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedAccount {
+    V2(Account),
+}
+
+impl From<VersionedAccount> for Account {
+    fn from(versioned: VersionedAccount) -> Self {
+        match versioned {
+            VersionedAccount::V2(account) => account,
+        }
+    }
+}
+
+impl From<Account> for VersionedAccount {
+    fn from(account: Account) -> Self {
+        VersionedAccount::V2(account)
+    }
+}
+
+/// Pending operation readable by humans.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanReadablePendingOperation {
+    pub kind: PendingOperationKind,
+    pub amount: U128,
+    pub shares: U128,
+    pub block_height: U64,
+    pub epoch_height: U64,
+}
+
+impl From<PendingOperation> for HumanReadablePendingOperation {
+    fn from(op: PendingOperation) -> Self {
+        Self {
+            kind: op.kind,
+            amount: op.amount.into(),
+            shares: op.shares.into(),
+            block_height: op.block_height.into(),
+            epoch_height: op.epoch_height.into(),
+        }
+    }
+}
+
+/// Unstake tranche readable by humans.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanReadableUnstakeTranche {
+    pub amount: U128,
+    pub available_epoch_height: U64,
+    pub can_withdraw: bool,
+}
+
+impl From<UnstakeTranche> for HumanReadableUnstakeTranche {
+    fn from(tranche: UnstakeTranche) -> Self {
+        Self {
+            amount: tranche.amount.into(),
+            available_epoch_height: tranche.available_epoch_height.into(),
+            can_withdraw: tranche.available_epoch_height <= env::epoch_height(),
+        }
     }
 }
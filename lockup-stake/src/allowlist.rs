@@ -0,0 +1,90 @@
+use crate::utils::mul_div;
+use crate::*;
+
+/// Allowlisted account readable by humans.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AllowlistEntry {
+    pub account_id: AccountId,
+    /// max staked balance (at the cached price) after a deposit, `None` for no limit
+    pub max_stake: Option<U128>,
+}
+
+impl StakingContract {
+    /// Checks the cap of an allowlisted account, its deposited balance included, for `amount` more.
+    /// Returns false if the account is not allowlisted
+    pub(crate) fn check_allowlist_deposit(&self, account_id: &AccountId, amount: Balance) -> bool {
+        match self.allowlist.get(account_id) {
+            None => false,
+            Some(None) => true,
+            Some(Some(max_stake)) => {
+                let account = self.internal_get_account(account_id);
+                let staked = mul_div(account.stake_shares, self.account_share_near_price(&account), ONE_E24);
+                assert!(
+                    staked + account.deposited_balance + amount <= max_stake,
+                    "The deposit exceeds the allowlist cap of {} yoctoNEAR",
+                    max_stake
+                );
+                true
+            }
+        }
+    }
+}
+
+///*********************/
+///* Allowlist methods */
+///*********************/
+#[near_bindgen]
+impl StakingContract {
+    /// Allows a non-lockup account (e.g. a DAO treasury) to stake, up to `max_stake` if set.
+    /// Also updates the cap of an account already in the allowlist
+    #[payable]
+    pub fn add_to_allowlist(&mut self, account_id: AccountId, max_stake: Option<U128>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.allowlist
+            .insert(&account_id, &max_stake.map(|max_stake| max_stake.0));
+        Event::AllowlistUpdated {
+            account_id,
+            max_stake,
+        }
+        .emit();
+    }
+
+    /// Removes an account from the allowlist. It can't deposit anymore,
+    /// but it can still unstake and withdraw its funds
+    #[payable]
+    pub fn remove_from_allowlist(&mut self, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            self.allowlist.remove(&account_id).is_some(),
+            "The account is not in the allowlist"
+        );
+        Event::AllowlistRemoved { account_id }.emit();
+    }
+
+    /// Returns the allowlist entry of an account, if any
+    pub fn get_allowlist_entry(&self, account_id: AccountId) -> Option<AllowlistEntry> {
+        self.allowlist.get(&account_id).map(|max_stake| AllowlistEntry {
+            account_id,
+            max_stake: max_stake.map(U128),
+        })
+    }
+
+    pub fn get_allowlist_len(&self) -> u64 {
+        self.allowlist.len()
+    }
+
+    /// Returns the allowlisted accounts
+    pub fn get_allowlist(&self, from_index: u64, limit: u64) -> Vec<AllowlistEntry> {
+        let keys = self.allowlist.keys_as_vector();
+        let values = self.allowlist.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| AllowlistEntry {
+                account_id: keys.get(index).unwrap(),
+                max_stake: values.get(index).unwrap().map(U128),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,219 @@
+use near_sdk::json_types::U64;
+
+use crate::metapool::MetaPoolBackend;
+use crate::staking_pool::StakingPoolBackend;
+use crate::utils::mul_div;
+use crate::*;
+
+/// Kind of contract the NEAR is staked with, selected at init
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Meta Pool liquid staking, with its lockup-specific methods
+    #[default]
+    MetaPool,
+    /// a core-contracts staking pool (single validator), with this contract as its only delegator
+    StakingPool,
+}
+
+/// The calls the lockup-facing accounting makes to the staking contract.
+/// Every promise resolves to what Meta Pool's lockup methods return, so the callbacks
+/// (`after_stake_for_lockup`, `after_unstake_shares`, ...) are shared by all backends
+pub trait StakingBackend {
+    /// Stakes `amount` (attached) for the account. Resolves to the shares minted, `U128`
+    fn stake(&self, account_id: &AccountId, amount: Balance) -> Promise;
+    /// Unstakes `shares`. Resolves to `(U128, U64)`: the NEAR unstaked and the epoch they can be withdrawn
+    fn unstake(&self, account_id: &AccountId, shares: NumStakeShares) -> Promise;
+    /// Sends `amount` of withdrawable NEAR to the account. Succeeds once sent
+    fn withdraw(&self, account_id: &AccountId, amount: Balance) -> Promise;
+    /// Stakes again `amount` of the account's unstaked balance. Resolves to `(U128, U128)`:
+    /// the shares minted and the NEAR sent to the account instead, if the backend released it but couldn't stake it.
+    /// `null` if the backend result can't be read
+    fn restake(&self, account_id: &AccountId, amount: Balance) -> Promise;
+    /// true if unstaked NEAR can be staked again before its unstake epoch
+    fn can_restake_unmatured(&self) -> bool;
+    /// gas of the `restake` calls, its callbacks included
+    fn restake_gas(&self) -> u64;
+    /// Resolves to the NEAR value of a share, `U128` with 24 decimals
+    fn get_share_price(&self) -> Promise;
+    /// Resolves to `MetaPoolContractState`, share price and reward fee
+    fn get_contract_state(&self) -> Promise;
+    /// Resolves to this contract's `MetaPoolAccountInfo`, if the backend has it
+    fn get_account_info(&self) -> Option<Promise>;
+    /// gas of the callback the backend adds on each call to adapt its results, 0 if none
+    fn extra_gas(&self) -> u64;
+    /// gas the backend adds to `stake` on top of `deposit_and_stake`, 0 if none
+    fn stake_extra_gas(&self) -> u64;
+    /// gas the backend adds to `unstake` on top of `unstake`, 0 if none
+    fn unstake_extra_gas(&self) -> u64;
+}
+
+/// The Meta Pool contract used before the last switch.
+/// Its accounts can still unstake and withdraw there, new deposits go to the current one
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PreviousBackend {
+    pub meta_pool_contract_id: AccountId,
+    pub total_stake_shares: U128,
+    pub total_unstaked_in_metapool: U128,
+    /// last stNEAR price received from it, used for its accounts' balances and `unstake(amount)`
+    pub st_near_price: U128,
+    /// when `st_near_price` was received
+    pub st_near_price_epoch_height: U64,
+}
+
+impl PreviousBackend {
+    pub fn is_empty(&self) -> bool {
+        self.total_stake_shares.0 == 0 && self.total_unstaked_in_metapool.0 == 0
+    }
+}
+
+impl StakingContract {
+    /// The backend at `contract_id`, of the kind selected at init
+    pub(crate) fn backend_at(&self, contract_id: AccountId) -> Box<dyn StakingBackend> {
+        let gas_config = self.gas_config.clone();
+        match self.backend_kind {
+            BackendKind::MetaPool => Box::new(MetaPoolBackend {
+                contract_id,
+                gas_config,
+            }),
+            BackendKind::StakingPool => Box::new(StakingPoolBackend {
+                contract_id,
+                gas_config,
+            }),
+        }
+    }
+
+    /// The backend receiving new deposits
+    pub(crate) fn backend(&self) -> Box<dyn StakingBackend> {
+        self.backend_at(self.meta_pool_contract_id.clone())
+    }
+
+    /// true if the account's funds are held at the previous Meta Pool contract
+    pub(crate) fn is_on_previous_backend(&self, account: &Account) -> bool {
+        account.has_backend_funds() && account.backend_generation != self.backend_generation
+    }
+
+    /// Meta Pool contract holding the account's shares and unstaked balance
+    pub(crate) fn account_meta_pool_contract_id(&self, account: &Account) -> AccountId {
+        match &self.previous_backend {
+            Some(previous) if self.is_on_previous_backend(account) => {
+                previous.meta_pool_contract_id.clone()
+            }
+            _ => self.meta_pool_contract_id.clone(),
+        }
+    }
+
+    /// stNEAR price of the Meta Pool contract holding the account's shares
+    pub(crate) fn account_share_near_price(&self, account: &Account) -> Balance {
+        match &self.previous_backend {
+            Some(previous) if self.is_on_previous_backend(account) => previous.st_near_price.0,
+            _ => self.share_near_price,
+        }
+    }
+
+    /// When the stNEAR price used for the account's balances was received
+    pub(crate) fn account_share_near_price_epoch_height(&self, account: &Account) -> EpochHeight {
+        match &self.previous_backend {
+            Some(previous) if self.is_on_previous_backend(account) => {
+                previous.st_near_price_epoch_height.0
+            }
+            _ => self.share_near_price_epoch_height,
+        }
+    }
+
+    /// Totals (stake shares, unstaked) of the Meta Pool contract holding the account's funds.
+    /// Goes by the account's generation only, so it still works once a withdraw emptied the account
+    pub(crate) fn backend_totals_mut(
+        &mut self,
+        account: &Account,
+    ) -> (&mut NumStakeShares, &mut Balance) {
+        if account.backend_generation != self.backend_generation {
+            if let Some(previous) = self.previous_backend.as_mut() {
+                return (
+                    &mut previous.total_stake_shares.0,
+                    &mut previous.total_unstaked_in_metapool.0,
+                );
+            }
+        }
+        (&mut self.total_stake_shares, &mut self.total_unstaked_in_metapool)
+    }
+
+    /// the tranches up to this epoch can be staked again with the current backend
+    pub(crate) fn restakable_epoch_height(&self) -> EpochHeight {
+        if self.backend().can_restake_unmatured() {
+            EpochHeight::MAX
+        } else {
+            env::epoch_height()
+        }
+    }
+
+    /// staked balance at both Meta Pool contracts, at the cached prices
+    pub(crate) fn internal_total_staked_balance(&self) -> Balance {
+        let previous = self.previous_backend.as_ref().map_or(0, |previous| {
+            mul_div(previous.total_stake_shares.0, previous.st_near_price.0, ONE_E24)
+        });
+        mul_div(self.total_stake_shares, self.share_near_price, ONE_E24) + previous
+    }
+
+    /// New deposits go to `meta_pool_contract_id`, the current one becomes the previous one.
+    /// Deposits are paused until the owner checks the new contract (e.g. `ping`) and resumes them
+    pub(crate) fn internal_switch_backend(&mut self, meta_pool_contract_id: AccountId) {
+        assert!(
+            meta_pool_contract_id != self.meta_pool_contract_id,
+            "It's already the current Meta Pool contract"
+        );
+        assert!(
+            self.previous_backend
+                .as_ref()
+                .map(PreviousBackend::is_empty)
+                .unwrap_or(true),
+            "All the funds must be withdrawn from the previous Meta Pool contract first"
+        );
+        // the totals only include migrated accounts
+        assert!(self.legacy_accounts.is_empty(), "Migrate all accounts first");
+
+        let old_meta_pool_contract_id =
+            std::mem::replace(&mut self.meta_pool_contract_id, meta_pool_contract_id.clone());
+        self.previous_backend = Some(PreviousBackend {
+            meta_pool_contract_id: old_meta_pool_contract_id.clone(),
+            total_stake_shares: std::mem::take(&mut self.total_stake_shares).into(),
+            total_unstaked_in_metapool: std::mem::take(&mut self.total_unstaked_in_metapool)
+                .into(),
+            st_near_price: self.share_near_price.into(),
+            st_near_price_epoch_height: self.share_near_price_epoch_height.into(),
+        });
+        self.backend_generation += 1;
+        // the first price from the new contract is not compared with the old one
+        self.share_near_price = ONE_NEAR;
+        self.share_near_price_epoch_height = 0;
+        self.staking_paused = true;
+        Event::MetaPoolContractSwitched {
+            old_meta_pool_contract_id,
+            meta_pool_contract_id,
+        }
+        .emit();
+    }
+}
+
+///*******************/
+///* Backend methods */
+///*******************/
+#[near_bindgen]
+impl StakingContract {
+    /// Returns the kind of contract the NEAR is staked with
+    pub fn get_backend_kind(&self) -> BackendKind {
+        self.backend_kind
+    }
+
+    /// Returns the Meta Pool contract used before the last switch, if any
+    pub fn get_previous_backend(&self) -> Option<PreviousBackend> {
+        self.previous_backend.clone()
+    }
+
+    /// Returns the Meta Pool contract holding the account's funds (the current one for empty accounts)
+    pub fn get_account_meta_pool_contract_id(&self, account_id: AccountId) -> AccountId {
+        self.account_meta_pool_contract_id(&self.internal_get_account(&account_id))
+    }
+}
@@ -0,0 +1,205 @@
+use near_sdk::json_types::{U128, U64};
+
+use crate::timelock::DEFAULT_ADMIN_ACTION_DELAY_EPOCHS;
+use crate::utils::mul_div;
+use crate::*;
+
+/// Default time a pending operation (busy lock) is trusted before `recover_account` can release it.
+/// Callbacks normally arrive within 3-5 blocks
+pub const DEFAULT_BUSY_LOCK_TIMEOUT_BLOCKS: u64 = 600;
+/// Lower bound, so an operation still in flight can not be recovered.
+/// Well above the delay of a receipt on a congested shard
+pub const MIN_BUSY_LOCK_TIMEOUT_BLOCKS: u64 = 300;
+/// Default max stNEAR price change accepted from Meta Pool in a single update (5%)
+pub const DEFAULT_MAX_PRICE_CHANGE_BP: u16 = 500;
+/// Default max reward fee accepted from Meta Pool (20%)
+pub const DEFAULT_MAX_REWARD_FEE_BP: u16 = 2000;
+/// we're managing lockup.accounts, keep a sane minimum
+pub const DEFAULT_MIN_DEPOSIT_AMOUNT: u128 = 10 * ONE_NEAR;
+
+/// Contract parameters adjustable by the owner
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    /// blocks after which a pending operation is considered stuck and anybody can call `recover_account`
+    pub busy_lock_timeout_blocks: U64,
+    /// `unstake(amount)` is rejected if the stNEAR price is older than this (0: never rejected)
+    pub max_price_age_epochs: U64,
+    /// stNEAR prices moving more than this (basis points) from the cached one are rejected
+    pub max_price_change_bp: u16,
+    /// if false, stNEAR prices lower than the cached one are rejected
+    pub allow_price_decrease: bool,
+    /// reward fees above this (basis points) are rejected
+    pub max_reward_fee_bp: u16,
+    /// pause deposits when a price or fee update is rejected
+    pub pause_on_rejected_update: bool,
+    /// smallest amount accepted by `deposit_and_stake`
+    pub min_deposit_amount: U128,
+    /// max staked balance (at the cached price) of an account after a deposit, `None` for no limit
+    pub max_account_stake: Option<U128>,
+    /// max total staked balance (at the cached price) after a deposit, `None` for no limit
+    pub max_total_stake: Option<U128>,
+    /// on their first deposit, check lockup accounts have this contract as staking pool
+    pub verify_lockup_accounts: bool,
+    /// epochs between queueing an admin action and executing it, changed with a queued action too
+    pub admin_action_delay_epochs: U64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            busy_lock_timeout_blocks: DEFAULT_BUSY_LOCK_TIMEOUT_BLOCKS.into(),
+            max_price_age_epochs: 0.into(),
+            max_price_change_bp: DEFAULT_MAX_PRICE_CHANGE_BP,
+            allow_price_decrease: false,
+            max_reward_fee_bp: DEFAULT_MAX_REWARD_FEE_BP,
+            pause_on_rejected_update: false,
+            min_deposit_amount: DEFAULT_MIN_DEPOSIT_AMOUNT.into(),
+            max_account_stake: None,
+            max_total_stake: None,
+            verify_lockup_accounts: false,
+            admin_action_delay_epochs: DEFAULT_ADMIN_ACTION_DELAY_EPOCHS.into(),
+        }
+    }
+}
+
+impl StakingContract {
+    /// Checks the deposit against `min_deposit_amount`, `max_account_stake` and `max_total_stake`
+    pub(crate) fn assert_deposit_within_limits(&self, account_id: &AccountId, amount: Balance) {
+        self.assert_min_deposit(amount);
+        self.assert_stake_within_limits(account_id, amount, amount);
+    }
+
+    pub(crate) fn assert_min_deposit(&self, amount: Balance) {
+        assert!(
+            amount >= self.config.min_deposit_amount.0,
+            "minimum deposit amount is {} yoctoNEAR",
+            self.config.min_deposit_amount.0
+        );
+    }
+
+    /// Checks `max_account_stake` for `added` NEAR more to the account staked and deposited balance,
+    /// and `max_total_stake` for `staked_amount` NEAR more staked
+    pub(crate) fn assert_stake_within_limits(
+        &self,
+        account_id: &AccountId,
+        added: Balance,
+        staked_amount: Balance,
+    ) {
+        if let Some(max_account_stake) = self.config.max_account_stake {
+            let account = self.internal_get_account(account_id);
+            let staked = mul_div(account.stake_shares, self.account_share_near_price(&account), ONE_E24);
+            assert!(
+                staked + account.deposited_balance + added <= max_account_stake.0,
+                "The deposit exceeds the max staked balance per account of {} yoctoNEAR",
+                max_account_stake.0
+            );
+        }
+        if let Some(max_total_stake) = self.config.max_total_stake {
+            let staked = self.internal_total_staked_balance();
+            assert!(
+                staked + staked_amount <= max_total_stake.0,
+                "The deposit exceeds the max total staked balance of {} yoctoNEAR",
+                max_total_stake.0
+            );
+        }
+    }
+}
+
+///*******************/
+///* Config methods  */
+///*******************/
+#[near_bindgen]
+impl StakingContract {
+    /// Sets how many blocks a pending operation is trusted before `recover_account` can release it
+    #[payable]
+    pub fn set_busy_lock_timeout_blocks(&mut self, blocks: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            blocks.0 >= MIN_BUSY_LOCK_TIMEOUT_BLOCKS,
+            "The timeout must be at least {} blocks",
+            MIN_BUSY_LOCK_TIMEOUT_BLOCKS
+        );
+        self.config.busy_lock_timeout_blocks = blocks;
+        Event::ConfigChanged {
+            config: self.config.clone(),
+        }
+        .emit();
+    }
+
+    /// Sets how many epochs the stNEAR price can be trusted by `unstake(amount)`, 0 to disable the check
+    #[payable]
+    pub fn set_max_price_age_epochs(&mut self, epochs: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.config.max_price_age_epochs = epochs;
+        Event::ConfigChanged {
+            config: self.config.clone(),
+        }
+        .emit();
+    }
+
+    /// Sets the bounds for the stNEAR price and reward fee received from Meta Pool.
+    /// Rejected updates keep the cached values and, if `pause_on_rejected_update`, pause deposits
+    #[payable]
+    pub fn set_price_guards(
+        &mut self,
+        max_price_change_bp: u16,
+        allow_price_decrease: bool,
+        max_reward_fee_bp: u16,
+        pause_on_rejected_update: bool,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(max_price_change_bp > 0, "max_price_change_bp must be positive");
+        assert!(max_reward_fee_bp <= 10_000, "max_reward_fee_bp can not exceed 10000");
+        self.config.max_price_change_bp = max_price_change_bp;
+        self.config.allow_price_decrease = allow_price_decrease;
+        self.config.max_reward_fee_bp = max_reward_fee_bp;
+        self.config.pause_on_rejected_update = pause_on_rejected_update;
+        Event::ConfigChanged {
+            config: self.config.clone(),
+        }
+        .emit();
+    }
+
+    /// Sets the deposit limits enforced by `deposit_and_stake`.
+    /// Staked balances are valued at the cached stNEAR price, deposits in flight are not counted
+    #[payable]
+    pub fn set_deposit_limits(
+        &mut self,
+        min_deposit_amount: U128,
+        max_account_stake: Option<U128>,
+        max_total_stake: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(min_deposit_amount.0 > 0, "min_deposit_amount must be positive");
+        self.config.min_deposit_amount = min_deposit_amount;
+        self.config.max_account_stake = max_account_stake;
+        self.config.max_total_stake = max_total_stake;
+        Event::ConfigChanged {
+            config: self.config.clone(),
+        }
+        .emit();
+    }
+
+    /// Enables querying `get_staking_pool_account_id` of lockup accounts on their first deposit.
+    /// Accounts not answering this contract are refunded
+    #[payable]
+    pub fn set_verify_lockup_accounts(&mut self, enabled: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.config.verify_lockup_accounts = enabled;
+        Event::ConfigChanged {
+            config: self.config.clone(),
+        }
+        .emit();
+    }
+
+    /// Returns the contract parameters
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+}
@@ -0,0 +1,161 @@
+//! NEP-297 events, logged as `EVENT_JSON:{"standard":"lockup_stake_metapool","version":..,"event":..,"data":{..}}`
+//! <https://github.com/near/NEPs/blob/master/neps/nep-0297.md>
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json;
+use near_sdk::{env, AccountId};
+
+use crate::account::{HumanReadablePendingOperation, PendingOperationKind};
+use crate::config::Config;
+use crate::gas::GasConfig;
+use crate::lockup::LockupAccountPatterns;
+use crate::roles::Role;
+use crate::timelock::AdminAction;
+
+pub const EVENT_STANDARD: &str = "lockup_stake_metapool";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+#[must_use = "don't forget to `.emit()` this event"]
+pub enum Event {
+    /// shares received from Meta Pool for a deposit
+    DepositAndStake {
+        account_id: AccountId,
+        amount: U128,
+        shares: U128,
+    },
+    /// stake at Meta Pool failed, the amount was returned to the account
+    DepositAndStakeFailed { account_id: AccountId, amount: U128 },
+    Unstake {
+        account_id: AccountId,
+        shares: U128,
+        amount: U128,
+        available_epoch_height: U64,
+    },
+    UnstakeFailed { account_id: AccountId, shares: U128 },
+    /// unstaked balance staked again with `stake`/`stake_all`
+    Restake {
+        account_id: AccountId,
+        amount: U128,
+        shares: U128,
+    },
+    /// the unstaked balance was not staked again, it's unchanged unless
+    /// Meta Pool had already released it: then it was sent to the account (see the `withdraw` event)
+    RestakeFailed { account_id: AccountId, amount: U128 },
+    /// sent by Meta Pool (or this contract, for the deposited balance) to the account
+    Withdraw { account_id: AccountId, amount: U128 },
+    /// NEAR received with `deposit`, held here until it's staked or withdrawn
+    Deposit { account_id: AccountId, amount: U128 },
+    /// deposited balance staked with `stake`/`stake_all`
+    Stake {
+        account_id: AccountId,
+        amount: U128,
+        shares: U128,
+    },
+    /// stake of the deposited balance failed, the amount is deposited again
+    StakeFailed { account_id: AccountId, amount: U128 },
+    WithdrawFailed { account_id: AccountId, amount: U128 },
+    /// Meta Pool call succeeded but its result could not be read, the account remains busy
+    OperationLeftPending {
+        account_id: AccountId,
+        kind: PendingOperationKind,
+        amount: U128,
+        shares: U128,
+    },
+    StNearPriceUpdated { st_near_price: U128 },
+    RewardFeeUpdated { reward_fee_bp: u16 },
+    /// the stNEAR price received from Meta Pool is out of the configured bounds, the cached one was kept
+    StNearPriceRejected {
+        st_near_price: U128,
+        cached_st_near_price: U128,
+    },
+    /// the reward fee received from Meta Pool is above the configured max, the cached one was kept
+    RewardFeeRejected {
+        reward_fee_bp: u16,
+        max_reward_fee_bp: u16,
+    },
+    /// a Meta Pool query made by `ping` failed, the cached value was kept
+    PingFailed {
+        method: String,
+        consecutive_failures: u32,
+    },
+    SetNotBusy {
+        account_id: AccountId,
+        operation: HumanReadablePendingOperation,
+    },
+    AccountRecovered {
+        account_id: AccountId,
+        operation: HumanReadablePendingOperation,
+        /// false if Meta Pool shows the operation did not go through
+        applied: bool,
+    },
+    OwnerChanged {
+        old_owner_id: AccountId,
+        new_owner_id: AccountId,
+    },
+    OwnerProposed {
+        owner_id: AccountId,
+        proposed_owner_id: AccountId,
+    },
+    OwnerProposalCancelled { proposed_owner_id: AccountId },
+    RoleGranted { role: Role, account_id: AccountId },
+    RoleRevoked { role: Role, account_id: AccountId },
+    StakingPaused {
+        paused_by: AccountId,
+        unstake_paused: bool,
+    },
+    StakingResumed { resumed_by: AccountId },
+    ConfigChanged { config: Config },
+    GasConfigChanged { gas_config: GasConfig },
+    LockupAccountPatternsChanged { patterns: LockupAccountPatterns },
+    /// new deposits go to `meta_pool_contract_id`, deposits are paused
+    MetaPoolContractSwitched {
+        old_meta_pool_contract_id: AccountId,
+        meta_pool_contract_id: AccountId,
+    },
+    AdminActionQueued {
+        id: U64,
+        action: AdminAction,
+        executable_epoch_height: U64,
+    },
+    AdminActionExecuted { id: U64, action: AdminAction },
+    AdminActionCancelled { id: U64, action: AdminAction },
+    AllowlistUpdated {
+        account_id: AccountId,
+        max_stake: Option<U128>,
+    },
+    AllowlistRemoved { account_id: AccountId },
+    /// the lockup answered this contract as its staking pool
+    LockupVerified { account_id: AccountId },
+    /// the lockup did not answer this contract as its staking pool, the deposit was returned
+    LockupVerificationFailed { account_id: AccountId, amount: U128 },
+    UpgradeStaged { code_hash: Base58CryptoHash },
+    UpgradeDeployed { code_hash: Base58CryptoHash },
+    /// the staged code was removed, its storage cost returned to the owner
+    UpgradeCancelled { code_hash: Base58CryptoHash },
+    AccountsMigrated { migrated: u64, pending: u64 },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Event {
+    /// Logs the event as `EVENT_JSON:...`
+    pub fn emit(self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event: &self,
+        };
+        // events can not fail to serialize
+        env::log_str(&format!("EVENT_JSON:{}", serde_json::to_string(&log).unwrap()));
+    }
+}
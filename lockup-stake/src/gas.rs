@@ -0,0 +1,195 @@
+use near_sdk::json_types::U64;
+
+use crate::utils::TGAS;
+use crate::*;
+
+// NOTE:
+// NEAR_WALLET, DEFAULT_GAS_ATTACHED on deposit_and_stake: 125*TGAS
+/// The foundation's near-core/lockup-contract USES:
+///  50TGAS for DEPOSIT
+///  75TGAS for DEPOSIT_AND_STAKE
+/// Requires 175TGAS for withdraw_all_from_staking_pool - https://github.com/near/core-contracts/blob/dad58eb5f968c25913e746028ad63980506f5890/lockup/src/owner.rs#L256
+pub const META_POOL_DEPOSIT_AND_STAKE_GAS: u64 = 30 * TGAS;
+pub const AFTER_STAKE_FOR_LOCKUP_GAS: u64 = 5 * TGAS;
+
+pub const META_POOL_WITHDRAW_GAS: u64 = 10 * TGAS;
+pub const AFTER_WITHDRAW_GAS: u64 = 5 * TGAS;
+
+pub const META_POOL_UNSTAKE_SHARES_GAS: u64 = 20 * TGAS;
+pub const AFTER_UNSTAKE_SHARES_GAS: u64 = 5 * TGAS;
+
+// Note: looks like that on promises, near core adds 5 extra TGAS on each call
+pub const GET_FUNCTION_GAS: u64 = 8 * TGAS;
+pub const AFTER_GET_FUNCTION_GAS: u64 = 4 * TGAS;
+pub const AFTER_PING_GAS: u64 = 6 * TGAS;
+pub const AFTER_RECOVER_ACCOUNT_GAS: u64 = 10 * TGAS;
+/// lockup contract `get_staking_pool_account_id`
+pub const LOCKUP_VIEW_GAS: u64 = 5 * TGAS;
+
+/// gas kept for the rest of the calling method, after scheduling its promises
+pub const EXECUTION_MARGIN_GAS: u64 = 15 * TGAS;
+
+/// bounds for the gas sent to Meta Pool on each call
+pub const MIN_META_POOL_CALL_GAS: u64 = 5 * TGAS;
+pub const MAX_META_POOL_CALL_GAS: u64 = 100 * TGAS;
+/// callbacks must not run out of gas (they clear the busy locks), so they can't get less than the defaults
+pub const MAX_CALLBACK_GAS: u64 = 50 * TGAS;
+
+/// Gas attached to the cross-contract calls, adjustable by the owner
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GasConfig {
+    /// Meta Pool `stake_for_lockup`
+    pub deposit_and_stake: U64,
+    pub after_deposit_and_stake: U64,
+    /// Meta Pool `unstake_from_lockup_shares`
+    pub unstake: U64,
+    pub after_unstake: U64,
+    /// Meta Pool `withdraw_to_lockup`
+    pub withdraw: U64,
+    pub after_withdraw: U64,
+    /// Meta Pool views: `get_st_near_price`, `get_contract_state`, `get_account_info`
+    pub view: U64,
+    pub after_get_st_near_price: U64,
+    pub after_ping: U64,
+    /// `recover_account` callback, after the `get_account_info` and `get_st_near_price` views
+    pub after_recover_account: U64,
+    /// lockup contract `get_staking_pool_account_id`, when lockup verification is enabled
+    pub lockup_view: U64,
+}
+
+impl Default for GasConfig {
+    fn default() -> Self {
+        Self {
+            deposit_and_stake: META_POOL_DEPOSIT_AND_STAKE_GAS.into(),
+            after_deposit_and_stake: AFTER_STAKE_FOR_LOCKUP_GAS.into(),
+            unstake: META_POOL_UNSTAKE_SHARES_GAS.into(),
+            after_unstake: AFTER_UNSTAKE_SHARES_GAS.into(),
+            withdraw: META_POOL_WITHDRAW_GAS.into(),
+            after_withdraw: AFTER_WITHDRAW_GAS.into(),
+            view: GET_FUNCTION_GAS.into(),
+            after_get_st_near_price: AFTER_GET_FUNCTION_GAS.into(),
+            after_ping: AFTER_PING_GAS.into(),
+            after_recover_account: AFTER_RECOVER_ACCOUNT_GAS.into(),
+            lockup_view: LOCKUP_VIEW_GAS.into(),
+        }
+    }
+}
+
+impl GasConfig {
+    fn assert_valid(&self) {
+        let default = Self::default();
+        for (name, gas) in [
+            ("deposit_and_stake", self.deposit_and_stake),
+            ("unstake", self.unstake),
+            ("withdraw", self.withdraw),
+            ("view", self.view),
+        ] {
+            assert!(
+                gas.0 >= MIN_META_POOL_CALL_GAS && gas.0 <= MAX_META_POOL_CALL_GAS,
+                "{} gas must be between {} and {} TGAS",
+                name,
+                MIN_META_POOL_CALL_GAS / TGAS,
+                MAX_META_POOL_CALL_GAS / TGAS
+            );
+        }
+        assert!(
+            self.lockup_view.0 >= LOCKUP_VIEW_GAS && self.lockup_view.0 <= MAX_META_POOL_CALL_GAS,
+            "lockup_view gas must be between {} and {} TGAS",
+            LOCKUP_VIEW_GAS / TGAS,
+            MAX_META_POOL_CALL_GAS / TGAS
+        );
+        for (name, gas, min) in [
+            ("after_deposit_and_stake", self.after_deposit_and_stake, default.after_deposit_and_stake),
+            ("after_unstake", self.after_unstake, default.after_unstake),
+            ("after_withdraw", self.after_withdraw, default.after_withdraw),
+            ("after_get_st_near_price", self.after_get_st_near_price, default.after_get_st_near_price),
+            ("after_ping", self.after_ping, default.after_ping),
+            ("after_recover_account", self.after_recover_account, default.after_recover_account),
+        ] {
+            assert!(
+                gas.0 >= min.0 && gas.0 <= MAX_CALLBACK_GAS,
+                "{} gas must be between {} and {} TGAS",
+                name,
+                min.0 / TGAS,
+                MAX_CALLBACK_GAS / TGAS
+            );
+        }
+    }
+
+    /// gas for `stake_for_lockup` and its callback
+    pub fn deposit_and_stake_chain(&self) -> u64 {
+        self.deposit_and_stake.0 + self.after_deposit_and_stake.0
+    }
+
+    /// gas for `unstake_from_lockup_shares` and its callback
+    pub fn unstake_chain(&self) -> u64 {
+        self.unstake.0 + self.after_unstake.0
+    }
+
+    /// gas for `withdraw_to_lockup` and its callback
+    pub fn withdraw_chain(&self) -> u64 {
+        self.withdraw.0 + self.after_withdraw.0
+    }
+
+    /// gas for a `get_st_near_price` query and its callback
+    pub fn price_refresh_chain(&self) -> u64 {
+        self.view.0 + self.after_get_st_near_price.0
+    }
+
+    /// gas for `after_verify_lockup`, which stakes at Meta Pool
+    pub fn after_verify_lockup(&self) -> u64 {
+        self.deposit_and_stake_chain() + 5 * TGAS
+    }
+
+    /// gas for the lockup verification query, its callback and the stake that follows
+    pub fn verify_lockup_chain(&self) -> u64 {
+        self.lockup_view.0 + self.after_verify_lockup()
+    }
+
+    /// gas for `after_get_st_near_price_for_unstake`, which unstakes at Meta Pool.
+    /// It runs the unstake pre-flight check, so it needs a margin on top of the one the check keeps
+    pub fn after_get_st_near_price_for_unstake(&self) -> u64 {
+        self.unstake_chain() + 2 * EXECUTION_MARGIN_GAS
+    }
+}
+
+/// true if, after reserving `reserved_gas` for the promises of the current call,
+/// the prepaid gas is enough for `extra_gas` more
+pub(crate) fn has_gas_for(reserved_gas: u64, extra_gas: u64) -> bool {
+    let available = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+    available >= reserved_gas + extra_gas + EXECUTION_MARGIN_GAS
+}
+
+/// fails early, before locking the account, if the prepaid gas can't cover the promises
+pub(crate) fn assert_gas_for(required_gas: u64) {
+    assert!(
+        has_gas_for(required_gas, 0),
+        "Not enough gas attached, at least {} TGAS required",
+        (env::used_gas().0 + required_gas + EXECUTION_MARGIN_GAS) / TGAS + 1
+    );
+}
+
+///*******************/
+///* Gas methods     */
+///*******************/
+#[near_bindgen]
+impl StakingContract {
+    /// Sets the gas attached to the calls to Meta Pool and to the callbacks
+    #[payable]
+    pub fn set_gas_config(&mut self, gas_config: GasConfig) {
+        assert_one_yocto();
+        self.assert_owner();
+        gas_config.assert_valid();
+        self.gas_config = gas_config;
+        Event::GasConfigChanged {
+            gas_config: self.gas_config.clone(),
+        }
+        .emit();
+    }
+
+    /// Returns the gas attached to the calls to Meta Pool and to the callbacks
+    pub fn get_gas_config(&self) -> GasConfig {
+        self.gas_config.clone()
+    }
+}
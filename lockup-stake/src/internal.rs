@@ -1,4 +1,6 @@
 //use crate::staking::ext_self;
+use near_sdk::log;
+
 use crate::*;
 
 impl StakingContract {
@@ -7,35 +9,115 @@ impl StakingContract {
     /********************/
 
     /// Inner method to get the given account or a new default value account.
+    /// Accounts not yet migrated are read from the legacy map.
     pub(crate) fn internal_get_account(&self, account_id: &AccountId) -> Account {
-        self.accounts.get(account_id).unwrap_or_default()
+        match self.accounts.get(account_id) {
+            Some(versioned) => versioned.into(),
+            None => self
+                .legacy_accounts
+                .get(account_id)
+                .map(Account::from)
+                .unwrap_or_default(),
+        }
     }
 
     /// Inner method to save the given account for a given account ID.
     /// If the account balances are 0, the account is deleted instead to release storage.
+    /// Saving a legacy account moves it to the versioned map.
     pub(crate) fn internal_save_account(&mut self, account_id: &AccountId, account: &Account) {
+        if !self.legacy_accounts.is_empty() {
+            if let Some(legacy) = self.legacy_accounts.remove(account_id) {
+                // from now on the account is included in the total
+                self.total_unstaked_in_metapool += legacy.unstaked_in_metapool;
+            }
+        }
         if account.is_empty() {
             self.accounts.remove(account_id);
         } else {
-            self.accounts.insert(account_id, &account);
+            self.accounts.insert(account_id, &account.clone().into());
+        }
+    }
+
+    /// Inner method to clear the pending operation (busy flag), should not panic.
+    /// Only clears it if it's the operation the callback expects, see `take_pending_operation`
+    pub(crate) fn clear_pending_operation(
+        &mut self,
+        account_id: &AccountId,
+        kind: PendingOperationKind,
+        amount: Balance,
+        shares: NumStakeShares,
+    ) -> bool {
+        let mut account = self.internal_get_account(account_id);
+        let taken = self.take_pending_operation(account_id, &mut account, kind, amount, shares);
+        if taken {
+            self.internal_save_account(account_id, &account);
         }
+        taken
     }
 
-    /// Inner method to remove busy flag, should not panic
-    pub(crate) fn clear_busy_flag(&mut self, account_id: &AccountId) {
-        let mut account = self.internal_get_account(&account_id);
-        if account.busy {
-            account.busy = false;
-            self.internal_save_account(&account_id, &account);
+    /// Removes the pending operation from `account` if it's the expected one.
+    /// A different (or no) pending operation is left untouched and logged, so it's not
+    /// released by a callback belonging to another call. Returns false in that case:
+    /// the operation was already settled (e.g. recovered) and the callback must not
+    /// apply its balance changes again. Should not panic
+    pub(crate) fn take_pending_operation(
+        &mut self,
+        account_id: &AccountId,
+        account: &mut Account,
+        kind: PendingOperationKind,
+        amount: Balance,
+        shares: NumStakeShares,
+    ) -> bool {
+        match &account.pending_operation {
+            Some(pending) if pending.matches(kind, amount, shares) => {
+                account.pending_operation = None;
+                self.operation_closed(kind);
+                true
+            }
+            pending => {
+                log!(
+                    "WARN: @{} callback for {:?} amount:{} shares:{} does not match pending operation {:?}",
+                    account_id,
+                    kind,
+                    amount,
+                    shares,
+                    pending
+                );
+                false
+            }
         }
     }
 
-    /// Inner method to SET busy flag. PANICS if flag already set
-    pub(crate) fn set_account_busy_flag_or_panic(&mut self, account_id: &AccountId) {
-        let mut account = self.internal_get_account(&account_id);
-        assert!(!account.busy, "The account is busy. Try again later");
-        account.busy = true;
-        self.internal_save_account(&account_id, &account);
+    /// Inner method to SET the pending operation (busy flag). PANICS if already busy
+    pub(crate) fn set_pending_operation_or_panic(
+        &mut self,
+        account_id: &AccountId,
+        kind: PendingOperationKind,
+        amount: Balance,
+        shares: NumStakeShares,
+    ) {
+        let mut account = self.internal_get_account(account_id);
+        assert!(!account.is_busy(), "The account is busy. Try again later");
+        // a staking pool mints shares for the change in its staked balance, which must be this operation's only
+        assert!(
+            self.backend_kind != BackendKind::StakingPool || self.in_flight_operations == 0,
+            "Another operation is in flight at the staking pool. Try again later"
+        );
+        if !account.has_backend_funds() {
+            // an account starting over stakes at the current Meta Pool contract
+            account.backend_generation = self.backend_generation;
+        }
+        account.pending_operation = Some(PendingOperation::new(kind, amount, shares));
+        self.internal_save_account(account_id, &account);
+        self.in_flight_operations += 1;
+    }
+
+    /// Counts out a pending operation that was cleared.
+    /// `Unknown` operations (migrated from v1.1.0) were never counted in
+    pub(crate) fn operation_closed(&mut self, kind: PendingOperationKind) {
+        if kind != PendingOperationKind::Unknown {
+            self.in_flight_operations = self.in_flight_operations.saturating_sub(1);
+        }
     }
 
 }
@@ -1,21 +1,47 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{UnorderedMap, UnorderedSet};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, ext_contract, near_bindgen, AccountId, Balance, Gas,
-    Promise, assert_one_yocto
+    env, ext_contract, near_bindgen, AccountId, Balance, CryptoHash, EpochHeight, Gas,
+    Promise, Timestamp, assert_one_yocto
 };
 use uint::construct_uint;
 
-use crate::account::{Account, NumStakeShares};
-pub use crate::views::HumanReadableAccount;
+use crate::account::{Account, AccountV1, NumStakeShares, PendingOperation, VersionedAccount};
+use crate::config::Config;
+use crate::events::Event;
+use crate::gas::GasConfig;
+pub use crate::lockup::LockupAccountPatterns;
+use crate::ping::PingStatus;
+pub use crate::account::{
+    HumanReadablePendingOperation, HumanReadableUnstakeTranche, PendingOperationKind,
+};
+pub use crate::allowlist::AllowlistEntry;
+pub use crate::backend::{BackendKind, PreviousBackend, StakingBackend};
+pub use crate::ping::PingResult;
+pub use crate::roles::Role;
+pub use crate::timelock::{AdminAction, QueuedAdminAction};
+pub use crate::views::{HumanReadableAccount, StNearPriceInfo};
 
 mod account;
+mod backend;
+mod allowlist;
+mod config;
+mod events;
+mod gas;
 mod internal;
+mod lockup;
+mod metapool;
+mod migration;
 mod owner;
 mod staking;
+mod staking_pool;
+mod timelock;
 mod ping;
+mod recovery;
+mod roles;
+mod upgrade;
 mod utils;
 
 mod views;
@@ -34,17 +60,67 @@ construct_uint! {
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct StakingContract {
     pub owner_id: AccountId,
+    /// proposed owner, becomes the owner when it calls `accept_ownership`
+    pub pending_owner_id: Option<AccountId>,
     /// The total amount of shares, should be equal to sum(accounts.shares).
     pub total_stake_shares: NumStakeShares,
+    /// sum(accounts.unstaked_in_metapool) of the accounts not in `legacy_accounts`
+    pub total_unstaked_in_metapool: Balance,
     /// Persistent map from an account ID to the corresponding account.
-    pub accounts: UnorderedMap<AccountId, Account>,
+    pub accounts: UnorderedMap<AccountId, VersionedAccount>,
+    /// Accounts stored with the v1.1.0 (untagged) layout, still waiting for `migrate_accounts`.
+    /// Reads fall back to this map, and any write moves the account into `accounts`.
+    pub legacy_accounts: UnorderedMap<AccountId, AccountV1>,
+    /// number of accounts with a pending operation set by this code (not `Unknown`):
+    /// cross-contract calls in flight, or stuck waiting for `recover_account` / `set_not_busy`
+    pub in_flight_operations: u64,
 
     // distributed, decentralized staking contract
     pub meta_pool_contract_id: AccountId,
+    /// kind of contract `meta_pool_contract_id` is, selected at init
+    pub backend_kind: BackendKind,
+    /// incremented each time `meta_pool_contract_id` is switched, see `Account::backend_generation`
+    pub backend_generation: u32,
+    /// the Meta Pool contract used before the last switch, its accounts can only unstake and withdraw
+    pub previous_backend: Option<PreviousBackend>,
     // how many nears a share (stNEAR) is worth (get from Meta Pool on ping)
     pub share_near_price: Balance,
+    /// when `share_near_price` was last received from Meta Pool (0 if never)
+    pub share_near_price_epoch_height: EpochHeight,
+    pub share_near_price_timestamp: Timestamp,
     // meta pool fee (get from Meta Pool on ping)
     pub meta_pool_fee_bp: u16,
+
+    /// sha256 of the code waiting in storage for `deploy_staged_upgrade`
+    pub staged_code_hash: Option<CryptoHash>,
+
+    /// emergency stop: new deposits are rejected while set
+    pub staking_paused: bool,
+    /// emergency stop: unstake is also rejected while set. Withdraw is always allowed.
+    pub unstake_paused: bool,
+    /// accounts allowed to ping, release stuck accounts and migrate accounts besides the owner
+    pub operators: UnorderedSet<AccountId>,
+    /// accounts allowed to pause (not to resume) besides the owner
+    pub guardians: UnorderedSet<AccountId>,
+
+    pub config: Config,
+
+    /// result of the last Meta Pool queries made by `ping`
+    pub ping_status: PingStatus,
+
+    /// gas attached to the cross-contract calls
+    pub gas_config: GasConfig,
+
+    /// accounts accepted as lockup accounts
+    pub lockup_account_patterns: LockupAccountPatterns,
+    /// non-lockup accounts allowed to stake, with their max staked balance
+    pub allowlist: UnorderedMap<AccountId, Option<Balance>>,
+    /// lockup accounts that answered this contract as their staking pool
+    pub verified_lockups: UnorderedSet<AccountId>,
+
+    /// owner actions waiting for their delay, by id
+    pub admin_actions: UnorderedMap<u64, QueuedAdminAction>,
+    pub next_admin_action_id: u64,
 }
 
 impl Default for StakingContract {
@@ -56,32 +132,68 @@ impl Default for StakingContract {
 #[near_bindgen]
 impl StakingContract {
     /// Initializes the contract 
+    /// `lockup_account_patterns` defaults to the mainnet and testnet lockup factories
     #[init]
     pub fn new(
         owner_id: AccountId,
         meta_pool_contract_id: AccountId,
+        lockup_account_patterns: Option<LockupAccountPatterns>,
+        backend_kind: Option<BackendKind>,
     ) -> Self {
         assert!(
             env::is_valid_account_id(owner_id.as_bytes()),
             "The owner account ID is invalid"
         );
+        let lockup_account_patterns = lockup_account_patterns.unwrap_or_default();
+        lockup_account_patterns.assert_valid();
+        migration::write_state_version();
         Self {
             owner_id,
+            pending_owner_id: None,
             total_stake_shares: 0,
-            accounts: UnorderedMap::new(b"a"),
+            total_unstaked_in_metapool: 0,
+            in_flight_operations: 0,
+            accounts: UnorderedMap::new(b"v"),
+            legacy_accounts: UnorderedMap::new(b"a"),
             meta_pool_contract_id,
+            backend_kind: backend_kind.unwrap_or_default(),
+            backend_generation: 0,
+            previous_backend: None,
             share_near_price: ONE_NEAR,
+            share_near_price_epoch_height: 0,
+            share_near_price_timestamp: 0,
             meta_pool_fee_bp: 400,
+            staged_code_hash: None,
+            staking_paused: false,
+            unstake_paused: false,
+            operators: UnorderedSet::new(b"o"),
+            guardians: UnorderedSet::new(b"g"),
+            config: Config::default(),
+            ping_status: PingStatus::default(),
+            gas_config: GasConfig::default(),
+            lockup_account_patterns,
+            allowlist: UnorderedMap::new(b"w"),
+            verified_lockups: UnorderedSet::new(b"k"),
+            admin_actions: UnorderedMap::new(b"t"),
+            next_admin_action_id: 0,
         }
     }
 
+    /// Clears the pending operation of an account stuck as busy.
+    /// Check `get_account_pending_operation` and reconcile against Meta Pool before calling.
     #[payable]
     pub fn set_not_busy(&mut self, account_id:AccountId) {
-        self.assert_owner();
+        self.assert_owner_or_role(Role::Operator);
         assert_one_yocto();
-        let mut acc = self.accounts.get(&account_id).unwrap();
-        acc.busy=false;
+        let mut acc = self.internal_get_account(&account_id);
+        let pending_operation = acc.pending_operation.take().expect("The account is not busy");
         self.internal_save_account(&account_id, &acc);
+        self.operation_closed(pending_operation.kind);
+        Event::SetNotBusy {
+            account_id,
+            operation: pending_operation.into(),
+        }
+        .emit();
 
     }
 
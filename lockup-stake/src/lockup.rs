@@ -0,0 +1,217 @@
+use near_sdk::{log, PromiseOrValue, PromiseResult};
+
+use crate::utils::TGAS;
+use crate::*;
+
+pub const ON_LOCKUP_REJECTED_GAS: u64 = 5 * TGAS;
+
+/// Accounts created by the lockup factories on mainnet and testnet
+pub const DEFAULT_LOCKUP_ACCOUNT_SUFFIXES: [&str; 2] = [".lockup.near", ".lockupy.testnet"];
+/// keeps the check cheap
+pub const MAX_LOCKUP_ACCOUNT_PATTERNS: usize = 20;
+
+/// Accounts accepted as lockup accounts: any account ending with one of `suffixes`,
+/// plus the accounts in `exceptions` (e.g. lockups on localnet or sandbox)
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockupAccountPatterns {
+    pub suffixes: Vec<String>,
+    pub exceptions: Vec<AccountId>,
+}
+
+impl Default for LockupAccountPatterns {
+    fn default() -> Self {
+        Self {
+            suffixes: DEFAULT_LOCKUP_ACCOUNT_SUFFIXES
+                .iter()
+                .map(|suffix| suffix.to_string())
+                .collect(),
+            exceptions: vec![],
+        }
+    }
+}
+
+impl LockupAccountPatterns {
+    /// verify if it a lockup account
+    pub fn matches(&self, account_id: &AccountId) -> bool {
+        self.suffixes
+            .iter()
+            .any(|suffix| account_id.as_str().ends_with(suffix.as_str()))
+            || self.exceptions.contains(account_id)
+    }
+
+    pub(crate) fn assert_valid(&self) {
+        assert!(
+            self.suffixes.len() + self.exceptions.len() <= MAX_LOCKUP_ACCOUNT_PATTERNS,
+            "At most {} lockup account patterns",
+            MAX_LOCKUP_ACCOUNT_PATTERNS
+        );
+        for suffix in self.suffixes.iter() {
+            // a suffix like "near" would match any account ending in "near", e.g. "evilnear"
+            assert!(
+                suffix.len() > 1 && suffix.starts_with('.'),
+                "Invalid lockup account suffix {}, it must start with a dot",
+                suffix
+            );
+        }
+    }
+}
+
+/// Interface for the lockup contract
+#[ext_contract(ext_lockup)]
+trait Lockup {
+    fn get_staking_pool_account_id(&self) -> Option<AccountId>;
+}
+/// Interface for the contract itself.
+#[ext_contract(ext_self)]
+pub trait SelfContract {
+    fn after_verify_lockup(&mut self, account_id: AccountId, amount: U128) -> Promise;
+    fn on_lockup_rejected(&mut self, account_id: AccountId);
+}
+
+impl StakingContract {
+    /// true if verification is enabled and the lockup account was not verified yet
+    pub(crate) fn needs_lockup_verification(&self, account_id: &AccountId) -> bool {
+        self.config.verify_lockup_accounts
+            && self.lockup_account_patterns.matches(account_id)
+            && self.allowlist.get(account_id).is_none()
+            && !self.verified_lockups.contains(account_id)
+    }
+
+    /// Asks the lockup for its staking pool, the account must be busy with the `DepositAndStake`
+    pub(crate) fn internal_verify_lockup(&self, account_id: AccountId, amount: Balance) -> Promise {
+        ext_lockup::get_staking_pool_account_id(
+            account_id.clone(),
+            0,
+            Gas(self.gas_config.lockup_view.0),
+        )
+        .then(ext_self::after_verify_lockup(
+            account_id,
+            amount.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(self.gas_config.after_verify_lockup() + self.backend().stake_extra_gas()),
+        ))
+    }
+
+    /// assert it is a lockup account, or an allowlisted account within its cap
+    pub(crate) fn assert_can_deposit(&self, account_id: &AccountId, amount: Balance) {
+        assert!(
+            self.lockup_account_patterns.matches(account_id)
+                || self.check_allowlist_deposit(account_id, amount),
+            "only lockup accounts or allowlisted accounts can be used here"
+        );
+    }
+
+    /// assert it is a lockup account or an allowlisted account.
+    /// Accounts removed from the allowlist can still take their funds out
+    pub(crate) fn assert_can_unstake_and_withdraw(&self, account_id: &AccountId) {
+        assert!(
+            self.lockup_account_patterns.matches(account_id)
+                || self.allowlist.get(account_id).is_some()
+                || !self.internal_get_account(account_id).is_empty(),
+            "only lockup accounts or allowlisted accounts can be used here"
+        );
+    }
+}
+
+///***************************/
+///* Lockup accounts methods */
+///***************************/
+#[near_bindgen]
+impl StakingContract {
+    /// Sets the accounts accepted as lockup accounts
+    #[payable]
+    pub fn set_lockup_account_patterns(&mut self, patterns: LockupAccountPatterns) {
+        assert_one_yocto();
+        self.assert_owner();
+        patterns.assert_valid();
+        self.lockup_account_patterns = patterns.clone();
+        Event::LockupAccountPatternsChanged { patterns }.emit();
+    }
+
+    /// Returns the accounts accepted as lockup accounts
+    pub fn get_lockup_account_patterns(&self) -> LockupAccountPatterns {
+        self.lockup_account_patterns.clone()
+    }
+
+    /// Returns `true` if the account can stake in this contract
+    pub fn is_lockup_account(&self, account_id: AccountId) -> bool {
+        self.lockup_account_patterns.matches(&account_id)
+    }
+
+    /// Returns `true` if the lockup account answered this contract as its staking pool
+    pub fn is_lockup_verified(&self, account_id: AccountId) -> bool {
+        self.verified_lockups.contains(&account_id)
+    }
+
+    #[private]
+    // continues after `internal_verify_lockup`
+    pub fn after_verify_lockup(&mut self, account_id: AccountId, amount: U128) -> PromiseOrValue<()> {
+        // WARN: This is a callback after-cross-contract-call method
+        // the account is busy and the deposit is held here, this method SHOULD NOT PANIC
+        let account = self.internal_get_account(&account_id);
+        let is_pending = account.pending_operation.as_ref().is_some_and(|pending| {
+            pending.matches(PendingOperationKind::DepositAndStake, amount.0, 0)
+        });
+        if !is_pending {
+            // already settled (recovered), the deposit was credited to the account
+            log!(
+                "WARN: @{} lockup verification for amount:{} does not match pending operation {:?}",
+                account_id,
+                amount.0,
+                account.pending_operation
+            );
+            return PromiseOrValue::Value(());
+        }
+        let staking_pool_account_id = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<Option<AccountId>>(&value)
+                    .ok()
+                    .flatten()
+            }
+            _ => None,
+        };
+        if staking_pool_account_id == Some(env::current_account_id()) {
+            self.verified_lockups.insert(&account_id);
+            Event::LockupVerified {
+                account_id: account_id.clone(),
+            }
+            .emit();
+            self.internal_stake_for_lockup(account_id, amount.0).into()
+        } else {
+            self.clear_pending_operation(
+                &account_id,
+                PendingOperationKind::DepositAndStake,
+                amount.0,
+                0,
+            );
+            Event::LockupVerificationFailed {
+                account_id: account_id.clone(),
+                amount,
+            }
+            .emit();
+            // return the NEAR, then fail so the caller sees the deposit failed
+            Promise::new(account_id.clone())
+                .transfer(amount.0)
+                .then(ext_self::on_lockup_rejected(
+                    account_id,
+                    //---
+                    env::current_account_id(),
+                    0,
+                    Gas(ON_LOCKUP_REJECTED_GAS),
+                ))
+                .into()
+        }
+    }
+
+    #[private]
+    // continues after the refund of a rejected deposit
+    pub fn on_lockup_rejected(&mut self, account_id: AccountId) {
+        panic!(
+            "@{} does not have this contract as staking pool, the deposit was returned",
+            account_id
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use near_sdk::{is_promise_success, PromiseResult};
+
+use crate::gas::GasConfig;
+use crate::utils::TGAS;
+use crate::*;
+
+/// gas for each callback of the restake chain
+pub const RESTAKE_CALLBACK_GAS: u64 = 5 * TGAS;
+
+/// Interface for Meta Pool
+#[ext_contract(ext_metapool)]
+trait MetaPool {
+    fn stake_for_lockup(&mut self, lockup_account_id: String) -> U128;
+    fn unstake_from_lockup_shares(&mut self, lockup_account_id: String, shares: U128) -> U64;
+    fn withdraw_to_lockup(&mut self, lockup_account_id: String, amount: U128) -> Promise;
+    fn get_st_near_price(&self) -> U128;
+    fn get_contract_state(&self) -> MetaPoolContractState;
+    fn get_account_info(&self, account_id: AccountId) -> MetaPoolAccountInfo;
+}
+/// Interface for the contract itself.
+#[ext_contract(ext_self)]
+pub trait SelfContract {
+    fn on_metapool_restake_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        meta_pool_contract_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+    fn on_metapool_restake_staked(&mut self, account_id: AccountId, amount: U128) -> Option<(U128, U128)>;
+}
+
+/// Meta Pool, the default backend. Shares are stNEAR, and the NEAR is withdrawn
+/// by Meta Pool straight to the lockup account
+pub struct MetaPoolBackend {
+    pub contract_id: AccountId,
+    pub gas_config: GasConfig,
+}
+
+impl StakingBackend for MetaPoolBackend {
+    fn stake(&self, account_id: &AccountId, amount: Balance) -> Promise {
+        ext_metapool::stake_for_lockup(
+            account_id.to_string(),
+            //---
+            self.contract_id.clone(),
+            amount, // send the NEAR
+            Gas(self.gas_config.deposit_and_stake.0),
+        )
+    }
+
+    fn unstake(&self, account_id: &AccountId, shares: NumStakeShares) -> Promise {
+        ext_metapool::unstake_from_lockup_shares(
+            account_id.to_string(),
+            shares.into(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.unstake.0),
+        )
+    }
+
+    fn withdraw(&self, account_id: &AccountId, amount: Balance) -> Promise {
+        // The NEAR will be sent directly to the lockup account
+        ext_metapool::withdraw_to_lockup(
+            account_id.to_string(),
+            amount.into(),
+            //--
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.withdraw.0),
+        )
+    }
+
+    fn restake(&self, account_id: &AccountId, amount: Balance) -> Promise {
+        // Meta Pool can't cancel an unstake: the matured NEAR is withdrawn to this contract and staked again
+        ext_metapool::withdraw_to_lockup(
+            env::current_account_id().to_string(),
+            amount.into(),
+            //--
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.withdraw.0),
+        )
+        .then(ext_self::on_metapool_restake_withdrawn(
+            account_id.clone(),
+            self.contract_id.clone(),
+            amount.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(self.gas_config.deposit_and_stake.0 + 2 * RESTAKE_CALLBACK_GAS),
+        ))
+    }
+
+    fn can_restake_unmatured(&self) -> bool {
+        false
+    }
+
+    fn restake_gas(&self) -> u64 {
+        self.gas_config.withdraw.0 + self.gas_config.deposit_and_stake.0 + 2 * RESTAKE_CALLBACK_GAS
+    }
+
+    fn get_share_price(&self) -> Promise {
+        ext_metapool::get_st_near_price(self.contract_id.clone(), 0, Gas(self.gas_config.view.0))
+    }
+
+    fn get_contract_state(&self) -> Promise {
+        ext_metapool::get_contract_state(self.contract_id.clone(), 0, Gas(self.gas_config.view.0))
+    }
+
+    fn get_account_info(&self) -> Option<Promise> {
+        Some(ext_metapool::get_account_info(
+            env::current_account_id(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        ))
+    }
+
+    fn extra_gas(&self) -> u64 {
+        0
+    }
+
+    fn stake_extra_gas(&self) -> u64 {
+        0
+    }
+
+    fn unstake_extra_gas(&self) -> u64 {
+        0
+    }
+}
+
+///****************************/
+///* Meta Pool restake chain  */
+///****************************/
+#[near_bindgen]
+impl StakingContract {
+    #[private]
+    // continues after `withdraw_to_lockup`. It may panic: nothing was released, so nothing changed
+    pub fn on_metapool_restake_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        meta_pool_contract_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        assert!(is_promise_success(), "The Meta Pool withdraw failed");
+        ext_metapool::stake_for_lockup(
+            account_id.to_string(),
+            //---
+            meta_pool_contract_id,
+            amount.0, // send the NEAR received
+            Gas(self.gas_config.deposit_and_stake.0),
+        )
+        .then(ext_self::on_metapool_restake_staked(
+            account_id,
+            amount,
+            //---
+            env::current_account_id(),
+            0,
+            Gas(RESTAKE_CALLBACK_GAS),
+        ))
+    }
+
+    #[private]
+    // continues after `stake_for_lockup`
+    pub fn on_metapool_restake_staked(&mut self, account_id: AccountId, amount: U128) -> Option<(U128, U128)> {
+        // SHOULD NOT PANIC, the NEAR was already released by Meta Pool
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .ok()
+                .map(|shares| (shares, 0.into())),
+            PromiseResult::Failed => {
+                // the deposit came back to this contract, it goes to the account as a withdrawal
+                Promise::new(account_id).transfer(amount.0);
+                Some((0.into(), amount))
+            }
+        }
+    }
+}
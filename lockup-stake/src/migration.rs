@@ -0,0 +1,144 @@
+use near_sdk::log;
+
+use crate::*;
+
+/// Storage key of the layout version of the contract state, kept out of the state struct
+/// so it can be read before knowing the layout. v1.1.0 did not write it
+const STATE_VERSION_KEY: &[u8] = b"state_version";
+/// Layout of the v1.1.0 state, `StakingContractV1`
+pub const STATE_VERSION_V1: u32 = 1;
+/// Layout of the current `StakingContract`.
+/// When the struct changes, freeze its current layout as `StakingContractV2`,
+/// bump this version and convert it in `migrate`
+pub const STATE_VERSION: u32 = 2;
+
+/// Layout version of the stored contract state
+pub(crate) fn read_state_version() -> u32 {
+    env::storage_read(STATE_VERSION_KEY)
+        .map(|value| u32::try_from_slice(&value).expect("Invalid state version"))
+        .unwrap_or(STATE_VERSION_V1)
+}
+
+/// Records that the stored contract state has the current layout
+pub(crate) fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.try_to_vec().unwrap());
+}
+
+/// Contract state as deployed in v1.1.0.
+/// Accounts were stored untagged in the map with prefix `a`.
+#[derive(BorshDeserialize)]
+pub struct StakingContractV1 {
+    pub owner_id: AccountId,
+    pub total_stake_shares: NumStakeShares,
+    pub accounts: UnorderedMap<AccountId, AccountV1>,
+    pub meta_pool_contract_id: AccountId,
+    pub share_near_price: Balance,
+    pub meta_pool_fee_bp: u16,
+}
+
+impl StakingContract {
+    /// The current state from the v1.1.0 state
+    fn from_v1(old: StakingContractV1) -> Self {
+        log!(
+            "migrating contract state, {} accounts pending",
+            old.accounts.len()
+        );
+        Self {
+            owner_id: old.owner_id,
+            pending_owner_id: None,
+            total_stake_shares: old.total_stake_shares,
+            // accumulated while accounts are migrated
+            total_unstaked_in_metapool: 0,
+            in_flight_operations: 0,
+            accounts: UnorderedMap::new(b"v"),
+            legacy_accounts: old.accounts,
+            meta_pool_contract_id: old.meta_pool_contract_id,
+            backend_kind: BackendKind::MetaPool,
+            backend_generation: 0,
+            previous_backend: None,
+            share_near_price: old.share_near_price,
+            // not recorded by v1.1.0, ping to refresh
+            share_near_price_epoch_height: 0,
+            share_near_price_timestamp: 0,
+            meta_pool_fee_bp: old.meta_pool_fee_bp,
+            staged_code_hash: None,
+            staking_paused: false,
+            unstake_paused: false,
+            operators: UnorderedSet::new(b"o"),
+            guardians: UnorderedSet::new(b"g"),
+            config: Config::default(),
+            ping_status: PingStatus::default(),
+            gas_config: GasConfig::default(),
+            lockup_account_patterns: LockupAccountPatterns::default(),
+            allowlist: UnorderedMap::new(b"w"),
+            verified_lockups: UnorderedSet::new(b"k"),
+            admin_actions: UnorderedMap::new(b"t"),
+            next_admin_action_id: 0,
+        }
+    }
+}
+
+///**********************/
+///* Migration methods  */
+///**********************/
+#[near_bindgen]
+impl StakingContract {
+    /// Upgrades the stored contract state to the current layout, from any layout version.
+    /// Must be called right after deploying new code, either by the owner or by the contract
+    /// itself (redeploy batched with this call).
+    /// Accounts are NOT rewritten here, see `migrate_accounts`.
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let contract = match read_state_version() {
+            STATE_VERSION_V1 => {
+                Self::from_v1(env::state_read().expect("Failed to read the contract state"))
+            }
+            STATE_VERSION => env::state_read().expect("Failed to read the contract state"),
+            version => panic!("Unknown contract state version {}", version),
+        };
+        let predecessor = env::predecessor_account_id();
+        assert!(
+            predecessor == contract.owner_id || predecessor == env::current_account_id(),
+            "Can only be called by the owner"
+        );
+        write_state_version();
+        contract
+    }
+
+    /// Returns the layout version of the stored contract state
+    pub fn get_state_version(&self) -> u32 {
+        read_state_version()
+    }
+
+    /// Moves up to `limit` accounts from the legacy storage into the versioned account storage.
+    /// Balances are kept as they are, a busy flag becomes an `Unknown` pending operation.
+    /// Call repeatedly until it returns 0.
+    /// Returns the number of accounts still pending migration.
+    pub fn migrate_accounts(&mut self, limit: u64) -> u64 {
+        self.assert_owner_or_role(Role::Operator);
+        let mut migrated = 0;
+        for _ in 0..limit {
+            let keys = self.legacy_accounts.keys_as_vector();
+            if keys.is_empty() {
+                break;
+            }
+            // take the last one, removing it from the UnorderedMap does not move other keys
+            let account_id = keys.get(keys.len() - 1).unwrap();
+            let account = self.legacy_accounts.remove(&account_id).unwrap();
+            self.total_unstaked_in_metapool += account.unstaked_in_metapool;
+            self.accounts.insert(&account_id, &Account::from(account).into());
+            migrated += 1;
+        }
+        Event::AccountsMigrated {
+            migrated,
+            pending: self.legacy_accounts.len(),
+        }
+        .emit();
+        self.legacy_accounts.len()
+    }
+
+    /// Returns the number of accounts still stored with the legacy layout.
+    pub fn get_accounts_pending_migration(&self) -> u64 {
+        self.legacy_accounts.len()
+    }
+}
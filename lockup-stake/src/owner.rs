@@ -1,3 +1,4 @@
+use near_sdk::json_types::U64;
 use near_sdk::{env::is_valid_account_id, assert_one_yocto};
 
 use crate::*;
@@ -8,17 +9,95 @@ use crate::*;
 #[near_bindgen]
 impl StakingContract {
 
-    /// Changes contract owner. Must be called by current owner.
+    /// Queues the proposal of a new contract owner, same as `propose_owner`.
     #[payable]
     pub fn set_owner_id(&mut self, new_owner_id: &AccountId) {
+        self.propose_owner(new_owner_id.clone());
+    }
+
+    /// Queues the proposal of a new contract owner. Must be called by current owner.
+    /// Once executed with `execute_admin_action` it replaces any previous proposal,
+    /// the owner doesn't change until the new owner calls `accept_ownership`.
+    /// Returns the admin action id
+    #[payable]
+    pub fn propose_owner(&mut self, new_owner_id: AccountId) -> U64 {
         assert_one_yocto();
-        assert!(is_valid_account_id(&new_owner_id.as_bytes()));
+        assert!(is_valid_account_id(new_owner_id.as_bytes()));
         assert_eq!(
             self.owner_id,
             env::predecessor_account_id(),
             "MUST BE OWNER TO SET OWNER"
         );
-        self.owner_id = new_owner_id.clone();
+        self.internal_queue_admin_action(AdminAction::ProposeOwner {
+            owner_id: new_owner_id,
+        })
+    }
+
+    /// Completes the ownership transfer. Must be called by the proposed owner.
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let new_owner_id = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_owner_id.as_ref(),
+            Some(&new_owner_id),
+            "Can only be called by the proposed owner"
+        );
+        self.pending_owner_id = None;
+        Event::OwnerChanged {
+            old_owner_id: self.owner_id.clone(),
+            new_owner_id: new_owner_id.clone(),
+        }
+        .emit();
+        self.owner_id = new_owner_id;
+    }
+
+    /// Cancels the ownership transfer. Must be called by current owner.
+    #[payable]
+    pub fn cancel_owner_proposal(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        let proposed_owner_id = self
+            .pending_owner_id
+            .take()
+            .expect("There is no owner proposal");
+        Event::OwnerProposalCancelled { proposed_owner_id }.emit();
+    }
+
+    /// Returns the proposed owner waiting to call `accept_ownership`, if any.
+    pub fn get_pending_owner_id(&self) -> Option<AccountId> {
+        self.pending_owner_id.clone()
+    }
+
+    /// Emergency stop. Rejects new deposits and, if `pause_unstake` is true, unstakes too.
+    /// Withdrawing already unstaked funds is always allowed.
+    /// Can be called by the owner or a guardian.
+    #[payable]
+    pub fn pause_staking(&mut self, pause_unstake: Option<bool>) {
+        assert_one_yocto();
+        self.assert_owner_or_role(Role::Guardian);
+        self.staking_paused = true;
+        if pause_unstake.unwrap_or(false) {
+            self.unstake_paused = true;
+        }
+        Event::StakingPaused {
+            paused_by: env::predecessor_account_id(),
+            unstake_paused: self.unstake_paused,
+        }
+        .emit();
+    }
+
+    /// Resumes deposits and unstakes. Only the owner can resume.
+    #[payable]
+    pub fn resume_staking(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.staking_paused = false;
+        self.unstake_paused = false;
+        Event::StakingResumed {
+            resumed_by: env::predecessor_account_id(),
+        }
+        .emit();
     }
 
     /// Asserts that the method was called by the owner.
@@ -30,4 +109,12 @@ impl StakingContract {
         );
     }
 
+    pub(crate) fn assert_staking_not_paused(&self) {
+        assert!(!self.staking_paused, "Staking is paused");
+    }
+
+    pub(crate) fn assert_unstake_not_paused(&self) {
+        assert!(!self.unstake_paused, "Unstaking is paused");
+    }
+
 }
@@ -1,71 +1,293 @@
 use near_sdk::env;
 
 use near_sdk::ext_contract;
+use near_sdk::json_types::U64;
+use near_sdk::PromiseResult;
 use crate::*;
-use crate::utils::TGAS;
+use crate::recovery::MetaPoolAccountInfo;
+use crate::utils::mul_div;
 
-// Note: looks like that on promises, near core adds 5 extra TGAS on each call
-pub const GET_FUNCTION_GAS: u64 = 8 * TGAS;
-pub const AFTER_GET_FUNCTION_GAS: u64 = 4 * TGAS;
-
-/// Interface for Meta Pool
-#[ext_contract(ext_metapool)]
-trait MetaPool {
-    fn get_st_near_price(&self) -> U128;
-    fn get_reward_fee_bp(&self) -> u16;
+/// Meta Pool contract state, only the fields used by `ping`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MetaPoolContractState {
+    pub st_near_price: U128,
+    pub operator_rewards_fee_basis_points: u16,
 }
+
 /// Interface for the contract itself.
 #[ext_contract(ext_self)]
 pub trait ThisContract {
     // callbacks to receive the result of view function
-    fn after_get_st_near_price(&self, #[callback] st_near_price: U128);
-    fn after_get_reward_fee_bp(&self, #[callback] bp: u16);
+    fn after_get_st_near_price(&mut self);
+    fn after_ping(&mut self) -> PingResult;
 }
 
-#[near_bindgen]
-impl StakingContract {
+/// Values held by the contract after `ping`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PingResult {
+    pub st_near_price: U128,
+    pub reward_fee_bp: u16,
+    /// this contract's stNEAR at Meta Pool, 0 if the query failed (or with a staking pool backend)
+    pub st_near_balance: U128,
+    /// false if the queries failed or the values were rejected, the cached ones are returned
+    pub updated: bool,
+}
 
-    /// gather info from meta pool, 
-    /// st_near_price and current fee
-    pub fn ping(&mut self) {
-        // call meta pool
-        // schedule 2 calls
-        // 1. try get_st_near_price
-        //log!("prepaid_gas {:?}, used_gas {:?}",env::prepaid_gas(), env::used_gas());
-        ext_metapool::get_st_near_price(
-            self.meta_pool_contract_id.clone(),
-            0,
-            Gas(GET_FUNCTION_GAS),
-        )
-        .then(ext_self::after_get_st_near_price(
+/// Outcome of the Meta Pool queries made by `ping` (and by the price refreshes of deposit and unstake)
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PingStatus {
+    /// last time a Meta Pool query succeeded
+    pub last_success_block_height: U64,
+    pub last_success_epoch_height: U64,
+    /// last time a Meta Pool query failed
+    pub last_failure_block_height: U64,
+    /// failed queries since the last success
+    pub consecutive_failures: u32,
+    /// this contract's stNEAR at Meta Pool on the last successful `ping`
+    pub st_near_balance: U128,
+    /// last time `ping` got the price but not this contract's stNEAR balance
+    pub last_balance_failure_block_height: U64,
+}
+
+impl Default for PingStatus {
+    fn default() -> Self {
+        Self {
+            last_success_block_height: 0.into(),
+            last_success_epoch_height: 0.into(),
+            last_failure_block_height: 0.into(),
+            consecutive_failures: 0,
+            st_near_balance: 0.into(),
+            last_balance_failure_block_height: 0.into(),
+        }
+    }
+}
+
+/// decodes the result of a `get_st_near_price` call, should not panic
+pub(crate) fn st_near_price_result() -> Option<U128> {
+    match env::promise_result(0) {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+            .ok()
+            .filter(|price| price.0 > 0),
+        _ => None,
+    }
+}
+
+impl StakingContract {
+    /// Queries the stNEAR price at Meta Pool, stored by `after_get_st_near_price`
+    pub(crate) fn internal_refresh_st_near_price(&self) -> Promise {
+        self.backend().get_share_price().then(ext_self::after_get_st_near_price(
             env::current_account_id(),
             0,
-            Gas(AFTER_GET_FUNCTION_GAS),
-        ));
-        // 2. try get_reward_fee_bp
-        //log!("prepaid_gas {:?}, used_gas {:?}",env::prepaid_gas(), env::used_gas());
-        ext_metapool::get_reward_fee_bp(
-            self.meta_pool_contract_id.clone(),
-            0,
-            Gas(GET_FUNCTION_GAS),
-        )
-        .then(ext_self::after_get_reward_fee_bp(
+            Gas(self.gas_config.after_get_st_near_price.0),
+        ))
+    }
+
+    fn internal_ping_succeeded(&mut self) {
+        self.ping_status.last_success_block_height = env::block_height().into();
+        self.ping_status.last_success_epoch_height = env::epoch_height().into();
+        self.ping_status.consecutive_failures = 0;
+    }
+
+    /// Checks the stNEAR price received from Meta Pool is within the configured bounds,
+    /// emits an event if it's not. Should not panic
+    fn check_share_near_price(&self, st_near_price: Balance) -> bool {
+        let cached = self.share_near_price;
+        let decreased = st_near_price < cached;
+        let change_bp = mul_div(
+            if decreased { cached - st_near_price } else { st_near_price - cached },
+            10_000,
+            cached,
+        );
+        // the max change is not checked until a first price is received from Meta Pool
+        let accepted = (self.config.allow_price_decrease || !decreased)
+            && (self.share_near_price_epoch_height == 0
+                || change_bp <= self.config.max_price_change_bp as u128);
+        if !accepted {
+            Event::StNearPriceRejected {
+                st_near_price: st_near_price.into(),
+                cached_st_near_price: cached.into(),
+            }
+            .emit();
+        }
+        accepted
+    }
+
+    /// Checks the reward fee received from Meta Pool is not above the configured max,
+    /// emits an event if it is. Should not panic
+    fn check_reward_fee_bp(&self, reward_fee_bp: u16) -> bool {
+        let accepted = reward_fee_bp <= self.config.max_reward_fee_bp;
+        if !accepted {
+            Event::RewardFeeRejected {
+                reward_fee_bp,
+                max_reward_fee_bp: self.config.max_reward_fee_bp,
+            }
+            .emit();
+        }
+        accepted
+    }
+
+    fn internal_set_share_near_price(&mut self, st_near_price: Balance) {
+        self.share_near_price = st_near_price;
+        self.share_near_price_epoch_height = env::epoch_height();
+        self.share_near_price_timestamp = env::block_timestamp();
+        Event::StNearPriceUpdated {
+            st_near_price: st_near_price.into(),
+        }
+        .emit();
+    }
+
+    /// Stores the stNEAR price received from Meta Pool if it's within the configured bounds.
+    /// Returns false if it was rejected. Should not panic
+    pub(crate) fn internal_update_share_near_price(&mut self, st_near_price: Balance) -> bool {
+        let accepted = self.check_share_near_price(st_near_price);
+        if accepted {
+            self.internal_set_share_near_price(st_near_price);
+        } else {
+            self.internal_pause_on_rejected_update();
+        }
+        accepted
+    }
+
+    fn internal_pause_on_rejected_update(&mut self) {
+        if self.config.pause_on_rejected_update && !self.staking_paused {
+            self.staking_paused = true;
+            Event::StakingPaused {
+                paused_by: env::current_account_id(),
+                unstake_paused: self.unstake_paused,
+            }
+            .emit();
+        }
+    }
+
+    /// epochs since the stNEAR price was received from Meta Pool
+    pub(crate) fn share_near_price_age_epochs(&self) -> EpochHeight {
+        env::epoch_height().saturating_sub(self.share_near_price_epoch_height)
+    }
+
+    pub(crate) fn assert_share_near_price_fresh(&self) {
+        let max_age = self.config.max_price_age_epochs.0;
+        let age = self.share_near_price_age_epochs();
+        assert!(
+            max_age == 0 || age <= max_age,
+            "The stNEAR price is {} epochs old, call ping first",
+            age
+        );
+    }
+
+    fn internal_ping_failed(&mut self, method: &str) {
+        self.ping_status.last_failure_block_height = env::block_height().into();
+        self.ping_status.consecutive_failures += 1;
+        Event::PingFailed {
+            method: method.into(),
+            consecutive_failures: self.ping_status.consecutive_failures,
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl StakingContract {
+
+    /// gather info from meta pool,
+    /// st_near_price, current fee and this contract's stNEAR, in a single callback
+    /// so price and fee are updated together. Returns the values held after the update.
+    /// Anybody can call it, e.g. a lockup owner before `unstake` when the price is too old
+    pub fn ping(&mut self) -> Promise {
+        let backend = self.backend();
+        let queries = match backend.get_account_info() {
+            Some(account_info) => backend.get_contract_state().and(account_info),
+            None => backend.get_contract_state(),
+        };
+        queries.then(ext_self::after_ping(
             env::current_account_id(),
             0,
-            Gas(AFTER_GET_FUNCTION_GAS),
-        ));
-        //log!("prepaid_gas {:?}, used_gas {:?}",env::prepaid_gas(), env::used_gas());
+            Gas(self.gas_config.after_ping.0),
+        ))
     }
     #[private]
     // continues after previous fn
-    pub fn after_get_st_near_price(&mut self, #[callback] st_near_price: U128) {
-        // Note/Warn: because it uses #[callback], this fn does not execute if the promise fails
-        self.share_near_price = st_near_price.0;
+    pub fn after_ping(&mut self) -> PingResult {
+        // WARN: This is a callback after-cross-contract-call method, SHOULD NOT PANIC
+        // do not use #[callback], it would not execute if the promise fails
+        let state = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<MetaPoolContractState>(&value)
+                    .ok()
+                    .filter(|state| state.st_near_price.0 > 0)
+            }
+            _ => None,
+        };
+        // the account info is not queried with a staking pool backend
+        let has_info = env::promise_results_count() > 1;
+        let info = if has_info {
+            match env::promise_result(1) {
+                PromiseResult::Successful(value) => {
+                    near_sdk::serde_json::from_slice::<MetaPoolAccountInfo>(&value).ok()
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let mut updated = false;
+        let mut st_near_balance = 0.into();
+        match state {
+            None => self.internal_ping_failed("get_contract_state"),
+            Some(state) => {
+                self.internal_ping_succeeded();
+                match info {
+                    Some(info) => {
+                        self.ping_status.st_near_balance = info.st_near;
+                        st_near_balance = info.st_near;
+                    }
+                    // price and fee don't depend on it, only the balance is missing
+                    None if has_info => {
+                        self.ping_status.last_balance_failure_block_height = env::block_height().into();
+                    }
+                    None => {}
+                }
+                // check both (events for each rejected value), apply both or none
+                let price_ok = self.check_share_near_price(state.st_near_price.0);
+                let fee_ok = self.check_reward_fee_bp(state.operator_rewards_fee_basis_points);
+                if price_ok && fee_ok {
+                    self.internal_set_share_near_price(state.st_near_price.0);
+                    self.meta_pool_fee_bp = state.operator_rewards_fee_basis_points;
+                    Event::RewardFeeUpdated {
+                        reward_fee_bp: self.meta_pool_fee_bp,
+                    }
+                    .emit();
+                    updated = true;
+                } else {
+                    self.internal_pause_on_rejected_update();
+                }
+            }
+        }
+        PingResult {
+            st_near_price: self.share_near_price.into(),
+            reward_fee_bp: self.meta_pool_fee_bp,
+            st_near_balance,
+            updated,
+        }
     }
     #[private]
-    // continues after previous fn
-    pub fn after_get_reward_fee_bp(&mut self, #[callback] bp: u16) {
-        // Note/Warn: because it uses #[callback], this fn does not execute if the promise fails
-        self.meta_pool_fee_bp = bp;
+    // continues after `internal_refresh_st_near_price`
+    pub fn after_get_st_near_price(&mut self) {
+        // WARN: This is a callback after-cross-contract-call method, SHOULD NOT PANIC
+        // do not use #[callback], it would not execute if the promise fails
+        match st_near_price_result() {
+            Some(st_near_price) => {
+                self.internal_ping_succeeded();
+                self.internal_update_share_near_price(st_near_price.0);
+            }
+            None => self.internal_ping_failed("get_st_near_price"),
+        }
+    }
+
+    /// Returns when Meta Pool was last queried successfully, and the failures since then
+    pub fn get_ping_status(&self) -> PingStatus {
+        self.ping_status.clone()
     }
 }
@@ -0,0 +1,203 @@
+use near_sdk::json_types::U64;
+use near_sdk::log;
+use near_sdk::PromiseResult;
+
+use crate::utils::mul_div;
+use crate::*;
+
+/// This contract's account in Meta Pool, only the fields used to reconcile
+#[derive(Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MetaPoolAccountInfo {
+    /// shares owned by this contract
+    pub st_near: U128,
+    /// unstaked balance waiting (or ready) to be withdrawn by this contract
+    pub unstaked: U128,
+    pub unstaked_requested_unlock_epoch: U64,
+}
+
+/// Interface for the contract itself.
+#[ext_contract(ext_self)]
+pub trait SelfContract {
+    fn after_recover_account_query(&mut self, account_id: AccountId, started_block_height: U64);
+}
+
+#[near_bindgen]
+impl StakingContract {
+    /// Releases an account whose pending operation expired (its callback never cleared it).
+    /// Anybody can call it. This contract's account at Meta Pool is queried to find out
+    /// if the operation went through, and the account is updated accordingly.
+    /// NOTE: differences between Meta Pool and this contract totals are attributed to this
+    /// account, so it's only reconciled when its operation is the only one pending
+    /// (`get_in_flight_operations` is 1). Otherwise the other operations must complete,
+    /// or be released with `set_not_busy`, first.
+    pub fn recover_account(&mut self, account_id: AccountId) -> Promise {
+        let account = self.internal_get_account(&account_id);
+        // the Meta Pool contract the operation was sent to
+        let backend = self.backend_at(self.account_meta_pool_contract_id(&account));
+        let account_info = backend
+            .get_account_info()
+            .expect("Only supported with Meta Pool, use set_not_busy");
+        let pending = account.pending_operation.expect("The account is not busy");
+        let expires_at = pending.block_height + self.config.busy_lock_timeout_blocks.0;
+        assert!(
+            env::block_height() >= expires_at,
+            "The pending operation expires at block {}",
+            expires_at
+        );
+        assert!(
+            pending.kind == PendingOperationKind::Unknown || self.in_flight_operations == 1,
+            "{} operations are pending, the account can only be recovered when it's the only one",
+            self.in_flight_operations
+        );
+
+        account_info.and(backend.get_share_price()).then(ext_self::after_recover_account_query(
+            account_id,
+            pending.block_height.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(self.gas_config.after_recover_account.0),
+        ))
+    }
+
+    #[private]
+    // continues after previous fn
+    pub fn after_recover_account_query(&mut self, account_id: AccountId, started_block_height: U64) {
+        // WARN: This is a callback after-cross-contract-call method, SHOULD NOT PANIC
+        let info = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<MetaPoolAccountInfo>(&value).ok()
+            }
+            _ => None,
+        };
+        let st_near_price = match env::promise_result(1) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<U128>(&value).ok()
+            }
+            _ => None,
+        };
+        let (info, st_near_price) = match (info, st_near_price) {
+            (Some(info), Some(price)) if price.0 > 0 => (info, price.0),
+            _ => {
+                log!("ERR: recover @{}, Meta Pool query failed", account_id);
+                return;
+            }
+        };
+
+        let mut account = self.internal_get_account(&account_id);
+        let pending = match account.pending_operation.clone() {
+            // another recover (or set_not_busy) got here first
+            Some(pending) if pending.block_height == started_block_height.0 => pending,
+            _ => {
+                log!("recover @{}, pending operation already cleared", account_id);
+                return;
+            }
+        };
+
+        if pending.kind != PendingOperationKind::Unknown && self.in_flight_operations != 1 {
+            // another operation started meanwhile, the totals can't be attributed to this account
+            log!(
+                "ERR: recover @{}, {} operations are pending, the account is left busy",
+                account_id,
+                self.in_flight_operations
+            );
+            return;
+        }
+
+        if matches!(pending.kind, PendingOperationKind::Withdraw | PendingOperationKind::Restake)
+            && !self.legacy_accounts.is_empty()
+        {
+            // the unstaked total only covers migrated accounts
+            log!("ERR: recover @{} withdraw, migrate all accounts first", account_id);
+            return;
+        }
+
+        let (total_stake_shares, total_unstaked_in_metapool) = self.backend_totals_mut(&account);
+        let applied = match pending.kind {
+            PendingOperationKind::DepositAndStake | PendingOperationKind::Stake => {
+                // shares minted by Meta Pool and not registered here
+                let unregistered = info.st_near.0.saturating_sub(*total_stake_shares);
+                let expected = mul_div(pending.amount, ONE_E24, st_near_price);
+                let shares = std::cmp::min(unregistered, expected);
+                account.stake_shares += shares;
+                *total_stake_shares += shares;
+                if shares < expected {
+                    log!(
+                        "WARN: recover @{} {:?} {}, registered {} shares of {} expected",
+                        account_id,
+                        pending.kind,
+                        pending.amount,
+                        shares,
+                        expected
+                    );
+                }
+                if shares == 0 {
+                    // the stake failed, the NEAR came back to this contract:
+                    // the account can withdraw it (or stake it) from its deposited balance
+                    account.deposited_balance += pending.amount;
+                }
+                shares > 0
+            }
+            PendingOperationKind::Unstake => {
+                // shares burned by Meta Pool and still registered here
+                let unregistered = total_stake_shares.saturating_sub(info.st_near.0);
+                let applied = unregistered >= pending.shares;
+                if applied {
+                    let unstaked_nears = mul_div(pending.shares, st_near_price, ONE_E24);
+                    account.stake_shares = account.stake_shares.saturating_sub(pending.shares);
+                    account.add_unstaked(unstaked_nears, info.unstaked_requested_unlock_epoch.0);
+                    *total_stake_shares -= pending.shares;
+                    *total_unstaked_in_metapool += unstaked_nears;
+                }
+                applied
+            }
+            PendingOperationKind::Withdraw => {
+                // unstaked balance withdrawn from Meta Pool and still registered here
+                let unregistered = total_unstaked_in_metapool.saturating_sub(info.unstaked.0);
+                let applied = unregistered >= pending.amount;
+                if applied {
+                    account.remove_unstaked(pending.amount);
+                    *total_unstaked_in_metapool -= pending.amount;
+                }
+                applied
+            }
+            PendingOperationKind::Restake => {
+                // unstaked balance withdrawn from Meta Pool to stake it again
+                let unregistered = total_unstaked_in_metapool.saturating_sub(info.unstaked.0);
+                let applied = unregistered >= pending.amount;
+                if applied {
+                    account.remove_restaked(pending.amount, env::epoch_height());
+                    *total_unstaked_in_metapool -= pending.amount;
+                    // and the shares minted for it, the NEAR not staked was sent to the account
+                    let minted = info.st_near.0.saturating_sub(*total_stake_shares);
+                    let expected = mul_div(pending.amount, ONE_E24, st_near_price);
+                    let shares = std::cmp::min(minted, expected);
+                    account.stake_shares += shares;
+                    *total_stake_shares += shares;
+                    if shares < expected {
+                        log!(
+                            "WARN: recover @{} restake {}, registered {} shares of {} expected",
+                            account_id,
+                            pending.amount,
+                            shares,
+                            expected
+                        );
+                    }
+                }
+                applied
+            }
+            // nothing to reconcile, just release
+            PendingOperationKind::Unknown => false,
+        };
+        account.pending_operation = None;
+        self.internal_save_account(&account_id, &account);
+        self.operation_closed(pending.kind);
+        Event::AccountRecovered {
+            account_id,
+            operation: pending.into(),
+            applied,
+        }
+        .emit();
+    }
+}
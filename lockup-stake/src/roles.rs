@@ -0,0 +1,79 @@
+use crate::*;
+
+/// Privileged roles besides the owner, which can do everything a role can.
+/// The owner manages config, roles and upgrades.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// ping, release stuck accounts, migrate accounts and execute admin actions whose delay has passed
+    Operator,
+    /// pause only
+    Guardian,
+}
+
+impl StakingContract {
+    fn role_members(&self, role: Role) -> &UnorderedSet<AccountId> {
+        match role {
+            Role::Operator => &self.operators,
+            Role::Guardian => &self.guardians,
+        }
+    }
+
+    fn role_members_mut(&mut self, role: Role) -> &mut UnorderedSet<AccountId> {
+        match role {
+            Role::Operator => &mut self.operators,
+            Role::Guardian => &mut self.guardians,
+        }
+    }
+
+    /// Asserts that the method was called by the owner or an account with the role.
+    pub(crate) fn assert_owner_or_role(&self, role: Role) {
+        let predecessor = env::predecessor_account_id();
+        assert!(
+            predecessor == self.owner_id || self.role_members(role).contains(&predecessor),
+            "Can only be called by the owner or an account with the {:?} role",
+            role
+        );
+    }
+}
+
+///*******************/
+///* Role methods    */
+///*******************/
+#[near_bindgen]
+impl StakingContract {
+    /// Grants the role to the account. Must be called by the owner.
+    #[payable]
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            self.role_members_mut(role).insert(&account_id),
+            "The account already has the role"
+        );
+        Event::RoleGranted { role, account_id }.emit();
+    }
+
+    /// Revokes the role from the account. Must be called by the owner.
+    #[payable]
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            self.role_members_mut(role).remove(&account_id),
+            "The account does not have the role"
+        );
+        Event::RoleRevoked { role, account_id }.emit();
+    }
+
+    /// Returns `true` if the account has the role (the owner is not included).
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        self.role_members(role).contains(&account_id)
+    }
+
+    /// Returns the accounts with the role (the owner is not included).
+    pub fn get_role_members(&self, role: Role) -> Vec<AccountId> {
+        self.role_members(role).to_vec()
+    }
+}
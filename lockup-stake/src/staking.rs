@@ -4,46 +4,28 @@ use near_sdk::log;
 use near_sdk::PromiseResult;
 
 use crate::ext_contract;
-use crate::utils::assert_is_lockup_account;
+use crate::gas::{assert_gas_for, has_gas_for};
+use crate::ping::st_near_price_result;
 use crate::utils::mul_div;
-use crate::utils::TGAS;
 use crate::*;
 
-// NOTE:
-// NEAR_WALLET, DEFAULT_GAS_ATTACHED on deposit_and_stake: 125*TGAS
-/// The foundation's near-core/lockup-contract USES:
-///  50TGAS for DEPOSIT
-///  75TGAS for DEPOSIT_AND_STAKE
-/// Requires 175TGAS for withdraw_all_from_staking_pool - https://github.com/near/core-contracts/blob/dad58eb5f968c25913e746028ad63980506f5890/lockup/src/owner.rs#L256
-pub const META_POOL_DEPOSIT_AND_STAKE_GAS: u64 = 30 * TGAS;
-pub const AFTER_STAKE_FOR_LOCKUP_GAS: u64 = 5 * TGAS;
-
-pub const META_POOL_WITHDRAW_GAS: u64 = 10 * TGAS;
-pub const AFTER_WITHDRAW_GAS: u64 = 5 * TGAS;
-
-pub const META_POOL_UNSTAKE_SHARES_GAS: u64 = 20 * TGAS;
-pub const AFTER_UNSTAKE_SHARES_GAS: u64 = 5 * TGAS;
-
-/// Interface for Meta Pool
-#[ext_contract(ext_metapool)]
-trait mp {
-    fn stake_for_lockup(&mut self, lockup_account_id: String) -> U128;
-    fn unstake_from_lockup_shares(&mut self, lockup_account_id: String, shares: U128) -> U64;
-    fn withdraw_to_lockup(&mut self, lockup_account_id: String, amount: U128) -> Promise;
-}
+/// Meta Pool can't cancel an unstake, the NEAR can only be staked again once released
+const UNMATURED_RESTAKE_ERROR: &str =
+    "Meta Pool can only stake again the unstaked balance whose wait period is over";
+
 /// Interface for the contract itself.
 #[ext_contract(ext_self)]
 pub trait SelfContract {
     /// A callback to check the result of the staking action.
     /// In case the stake failed, this callback rollbacks changes
     fn after_stake_for_lockup(&mut self, account_id: AccountId, deposited_amount: U128);
-    fn after_metapool_withdraw_to_lockup(&mut self, account_id: AccountId, amount: U128);
+    fn after_metapool_withdraw_to_lockup(&mut self, account_id: AccountId, amount: U128, deposited: U128);
     fn after_unstake_shares(&mut self, account_id: AccountId, num_shares: U128);
+    fn after_get_st_near_price_for_unstake(&mut self, account_id: AccountId, amount: U128) -> Promise;
+    fn after_restake(&mut self, account_id: AccountId, amount: U128);
+    fn after_stake_deposited(&mut self, account_id: AccountId, amount: U128);
 }
 
-const NOT_SUPPORTED_PLEASE_USE_DEPOSIT_AND_STAKE: &str =
-    "not supported, please use deposit_and_stake";
-
 #[near_bindgen]
 impl StakingContract {
     // =====================
@@ -53,62 +35,346 @@ impl StakingContract {
     // Note: In the reference contract near-core/staking-pool, depositing and staking
     // can be performed separately or in a single call:
     // There are functions called: `deposit` then `stake` and `stake_all`, and the composed `deposit_and_stake`.
-    // To increase safety and simplicity, we only support the simpler `deposit_and_stake` method.
-    // By removing the concept of "locally deposited balance" the contract becomes simpler and thus more secure.
+    // Both ways are supported: `deposit` holds the NEAR here (`deposited_balance`) until it's staked or withdrawn.
     // Note 1: All unstake and withdraw functions are supported.
     // Note 2: The standard wallet uses deposit_and_stake when dealing with lockup accounts
+    // Note 3: `stake` and `stake_all` also stake again the unstaked balance held by the backend
+
+    /// Deposits the attached amount into the inner account of the predecessor.
+    /// It's held by this contract, unstaked, until `stake` or `withdraw`.
     #[payable]
     pub fn deposit(&mut self) {
-        panic!("{}", NOT_SUPPORTED_PLEASE_USE_DEPOSIT_AND_STAKE);
+        self.assert_staking_not_paused();
+        let account_id = env::predecessor_account_id();
+        let amount = env::attached_deposit();
+        assert!(amount > 0, "Deposit amount should be positive");
+        self.assert_can_deposit(&account_id, amount);
+        self.assert_min_deposit(amount);
+        // the deposit is not staked yet, it only counts for the account cap
+        self.assert_stake_within_limits(&account_id, amount, 0);
+        // the verification stakes right after the lockup answers
+        assert!(
+            !self.needs_lockup_verification(&account_id),
+            "The lockup account is not verified yet, use deposit_and_stake"
+        );
+        let mut account = self.internal_get_account(&account_id);
+        account.deposited_balance += amount;
+        self.internal_save_account(&account_id, &account);
+        Event::Deposit {
+            account_id,
+            amount: amount.into(),
+        }
+        .emit();
     }
 
-    /// Stakes all available unstaked balance from the inner account of the predecessor.
+    /// Stakes all available unstaked balance from the inner account of the predecessor:
+    /// the deposited balance if any, else the unstaked balance at the backend.
+    /// With Meta Pool only the matured tranches are available (see `get_account_unstake_tranches`):
+    /// it can't cancel an unstake, so the tranches still waiting can't be staked until their epoch.
     pub fn stake_all(&mut self) -> Promise {
-        panic!("{}", NOT_SUPPORTED_PLEASE_USE_DEPOSIT_AND_STAKE);
+        let account_id = env::predecessor_account_id();
+        let account = self.internal_get_account(&account_id);
+        if account.deposited_balance > 0 {
+            return self.perform_stake_deposited(&account_id, account.deposited_balance);
+        }
+        let amount = account.matured_unstaked(self.restakable_epoch_height());
+        assert!(
+            amount > 0 || account.unstaked_in_metapool == 0,
+            "{}",
+            UNMATURED_RESTAKE_ERROR
+        );
+        self.perform_restake(&account_id, amount)
     }
 
     /// Stakes the given amount from the inner account of the predecessor.
-    /// The inner account should have enough unstaked balance.
-    #[allow(unused_variables)]
+    /// The inner account should have enough unstaked balance: it's taken from the
+    /// deposited balance if it covers the amount, else from the unstaked balance at the backend.
+    /// With Meta Pool the amount must be matured (see `get_account_withdrawable_balance`),
+    /// the tranches still waiting for their epoch can't be staked again until then.
     pub fn stake(&mut self, amount: U128) -> Promise {
-        panic!("{}", NOT_SUPPORTED_PLEASE_USE_DEPOSIT_AND_STAKE);
+        let account_id = env::predecessor_account_id();
+        let amount: Balance = amount.into();
+        if amount <= self.internal_get_account(&account_id).deposited_balance {
+            self.perform_stake_deposited(&account_id, amount)
+        } else {
+            self.perform_restake(&account_id, amount)
+        }
+    }
+
+    fn perform_stake_deposited(&mut self, account_id: &AccountId, amount: Balance) -> Promise {
+        self.assert_staking_not_paused();
+        assert!(amount > 0, "Staking amount should be positive");
+        let account = self.internal_get_account(account_id);
+        assert!(
+            !self.is_on_previous_backend(&account),
+            "The account has funds at the previous Meta Pool contract, unstake and withdraw them first"
+        );
+        assert!(
+            account.deposited_balance >= amount,
+            "Not enough deposited balance to stake"
+        );
+        // the deposited balance is already counted in the account caps, which may have been lowered since.
+        // The minimum was checked on deposit, any part of it can be staked
+        self.assert_can_deposit(account_id, 0);
+        self.assert_stake_within_limits(account_id, 0, amount);
+        assert_gas_for(self.gas_config.deposit_and_stake_chain() + self.backend().stake_extra_gas());
+
+        // avoiding re-entry
+        self.set_pending_operation_or_panic(account_id, PendingOperationKind::Stake, amount, 0);
+        // the amount is in flight, it's deposited again if the stake fails
+        let mut account = self.internal_get_account(account_id);
+        account.deposited_balance -= amount;
+        self.internal_save_account(account_id, &account);
+        self.backend().stake(account_id, amount).then(ext_self::after_stake_deposited(
+            account_id.clone(),
+            amount.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(self.gas_config.after_deposit_and_stake.0),
+        ))
+    }
+    #[private]
+    // continues after previous fn
+    pub fn after_stake_deposited(&mut self, account_id: AccountId, amount: U128) {
+        // WARN: This is a callback after-cross-contract-call method
+        // pending operations (busy locks) must be cleared in the state, this method SHOULD NOT PANIC
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+
+            PromiseResult::Successful(value) => {
+                if let Ok(num_shares) = near_sdk::serde_json::from_slice::<U128>(&value) {
+                    let num_shares = num_shares.0;
+                    let mut account = self.internal_get_account(&account_id);
+                    if !self.take_pending_operation(
+                        &account_id,
+                        &mut account,
+                        PendingOperationKind::Stake,
+                        amount.0,
+                        0,
+                    ) {
+                        return;
+                    }
+                    account.stake_shares += num_shares;
+                    *self.backend_totals_mut(&account).0 += num_shares;
+                    self.internal_save_account(&account_id, &account);
+                    Event::Stake {
+                        account_id,
+                        amount,
+                        shares: num_shares.into(),
+                    }
+                    .emit();
+                } else {
+                    // promise ok but no result? -- should not happen
+                    // the pending operation is kept, so it can be reconciled
+                    Event::OperationLeftPending {
+                        account_id,
+                        kind: PendingOperationKind::Stake,
+                        amount,
+                        shares: 0.into(),
+                    }
+                    .emit();
+                }
+            }
+
+            PromiseResult::Failed => {
+                // stake failed, the NEAR came back to this contract: ROLLBACK
+                let mut account = self.internal_get_account(&account_id);
+                if !self.take_pending_operation(
+                    &account_id,
+                    &mut account,
+                    PendingOperationKind::Stake,
+                    amount.0,
+                    0,
+                ) {
+                    return;
+                }
+                account.deposited_balance += amount.0;
+                self.internal_save_account(&account_id, &account);
+                Event::StakeFailed { account_id, amount }.emit();
+            }
+        }
+    }
+
+    fn perform_restake(&mut self, account_id: &AccountId, amount: Balance) -> Promise {
+        self.assert_staking_not_paused();
+        self.assert_can_unstake_and_withdraw(account_id);
+        assert!(amount > 0, "Staking amount should be positive");
+        let account = self.internal_get_account(account_id);
+        assert!(
+            !self.is_on_previous_backend(&account),
+            "The account has funds at the previous Meta Pool contract, unstake and withdraw them first"
+        );
+        assert!(
+            account.unstaked_in_metapool >= amount,
+            "Not enough unstaked balance to stake"
+        );
+        // Meta Pool releases the NEAR to stake it again, so the wait period must be over
+        assert!(
+            account.matured_unstaked(self.restakable_epoch_height()) >= amount,
+            "{}",
+            UNMATURED_RESTAKE_ERROR
+        );
+        // not a deposit: the minimum doesn't apply to staking again what was staked
+        self.assert_stake_within_limits(account_id, amount, amount);
+
+        let backend = self.backend();
+        assert_gas_for(backend.restake_gas() + self.gas_config.after_deposit_and_stake.0);
+        // avoiding re-entry
+        self.set_pending_operation_or_panic(account_id, PendingOperationKind::Restake, amount, 0);
+        backend.restake(account_id, amount).then(ext_self::after_restake(
+            account_id.clone(),
+            amount.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(self.gas_config.after_deposit_and_stake.0),
+        ))
+    }
+    #[private]
+    // continues after previous fn
+    pub fn after_restake(&mut self, account_id: AccountId, amount: U128) {
+        // WARN: This is a callback after-cross-contract-call method
+        // pending operations (busy locks) must be cleared in the state, this method SHOULD NOT PANIC
+        let amount = amount.0;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+
+            PromiseResult::Successful(value) => {
+                if let Ok((num_shares, returned)) =
+                    near_sdk::serde_json::from_slice::<(U128, U128)>(&value)
+                {
+                    let num_shares = num_shares.0;
+                    let mut account = self.internal_get_account(&account_id);
+                    if !self.take_pending_operation(
+                        &account_id,
+                        &mut account,
+                        PendingOperationKind::Restake,
+                        amount,
+                        0,
+                    ) {
+                        return;
+                    }
+                    // the amount left the unstaked balance, staked or sent to the account
+                    account.remove_restaked(amount, self.restakable_epoch_height());
+                    account.stake_shares += num_shares;
+                    let (total_stake_shares, total_unstaked_in_metapool) =
+                        self.backend_totals_mut(&account);
+                    *total_stake_shares += num_shares;
+                    *total_unstaked_in_metapool = total_unstaked_in_metapool.saturating_sub(amount);
+                    self.internal_save_account(&account_id, &account);
+                    if returned.0 == 0 {
+                        Event::Restake {
+                            account_id,
+                            amount: amount.into(),
+                            shares: num_shares.into(),
+                        }
+                        .emit();
+                    } else {
+                        Event::RestakeFailed {
+                            account_id: account_id.clone(),
+                            amount: amount.into(),
+                        }
+                        .emit();
+                        Event::Withdraw {
+                            account_id,
+                            amount: returned,
+                        }
+                        .emit();
+                    }
+                } else {
+                    // promise ok but no result? -- the backend could not read its own result
+                    // the pending operation is kept, so it can be reconciled
+                    Event::OperationLeftPending {
+                        account_id,
+                        kind: PendingOperationKind::Restake,
+                        amount: amount.into(),
+                        shares: 0.into(),
+                    }
+                    .emit();
+                }
+            }
+
+            PromiseResult::Failed => {
+                // nothing was released by the backend, the unstaked balance is unchanged
+                if !self.clear_pending_operation(
+                    &account_id,
+                    PendingOperationKind::Restake,
+                    amount,
+                    0,
+                ) {
+                    return;
+                }
+                Event::RestakeFailed {
+                    account_id,
+                    amount: amount.into(),
+                }
+                .emit();
+            }
+        }
     }
 
     /// Deposits the attached amount into the inner account of the predecessor and stakes it.
     /// Note: The foundation-s near-core/lockup-contract USES 50GAS for this call
     #[payable]
     pub fn deposit_and_stake(&mut self) -> Promise {
+        self.assert_staking_not_paused();
         let account_id = env::predecessor_account_id();
-        assert_is_lockup_account(&account_id);
         let amount = env::attached_deposit();
-
-        // we're managing lockup.accounts, keep a sane minimum
-        assert!(amount >= 10 * ONE_NEAR, "minimum deposit amount is 10 NEAR");
+        assert!(
+            !self.is_on_previous_backend(&self.internal_get_account(&account_id)),
+            "The account has funds at the previous Meta Pool contract, unstake and withdraw them first"
+        );
+        self.assert_can_deposit(&account_id, amount);
+
+        self.assert_deposit_within_limits(&account_id, amount);
+        let verify_lockup = self.needs_lockup_verification(&account_id);
+        let backend_gas = self.backend().stake_extra_gas();
+        let stake_gas = self.gas_config.deposit_and_stake_chain() + backend_gas;
+        assert_gas_for(if verify_lockup {
+            self.gas_config.verify_lockup_chain() + backend_gas
+        } else {
+            stake_gas
+        });
 
         // avoiding re-entry
-        self.set_account_busy_flag_or_panic(&account_id);
+        self.set_pending_operation_or_panic(
+            &account_id,
+            PendingOperationKind::DepositAndStake,
+            amount,
+            0,
+        );
+        if verify_lockup {
+            // stakes once the lockup answers, see `after_verify_lockup`
+            return self.internal_verify_lockup(account_id, amount);
+        }
+        // Meta Pool computes the shares, but refresh the cached price if the gas allows.
+        // A staking pool's price can't be read while the stake is in flight, `ping` refreshes it
+        if self.backend_kind == BackendKind::MetaPool
+            && has_gas_for(stake_gas, self.gas_config.price_refresh_chain())
+        {
+            self.internal_refresh_st_near_price();
+        }
+        self.internal_stake_for_lockup(account_id, amount)
+    }
+
+    /// Sends the deposit to the backend. The account must be busy with the `DepositAndStake`
+    pub(crate) fn internal_stake_for_lockup(&self, account_id: AccountId, amount: Balance) -> Promise {
         // call meta pool to stake
-        ext_metapool::stake_for_lockup(
-            account_id.to_string(),
-            //---
-            self.meta_pool_contract_id.clone(),
-            amount, // send the NEAR
-            Gas(META_POOL_DEPOSIT_AND_STAKE_GAS),
-            )
-        .then(ext_self::after_stake_for_lockup(
+        self.backend().stake(&account_id, amount).then(ext_self::after_stake_for_lockup(
             account_id,
             amount.into(),
             //---
             env::current_account_id(),
             0,
-            Gas(AFTER_STAKE_FOR_LOCKUP_GAS),
+            Gas(self.gas_config.after_deposit_and_stake.0),
         ))
     }
     #[private]
     // continues after previous fn
     pub fn after_stake_for_lockup(&mut self, account_id: AccountId, deposited_amount: U128) {
         // WARN: This is a callback after-cross-contract-call method
-        // busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        // pending operations (busy locks) must be cleared in the state, this method SHOULD NOT PANIC
         // SO DO NOT USE `#[callback]num_shares:U128` arguments, decode the return value manually
 
         // Check promise result and det the received_nears from the promise result.
@@ -118,30 +384,57 @@ impl StakingContract {
             PromiseResult::Successful(value) => {
                 if let Ok(num_shares) = near_sdk::serde_json::from_slice::<U128>(&value) {
                     let num_shares = num_shares.0;
-                    log!(
-                        "@{} deposit_and_stake {} received {} shares",
-                        account_id,
-                        deposited_amount.0,
-                        num_shares
-                    );
                     // register shares received
                     let mut account = self.internal_get_account(&account_id);
-                    account.busy = false;
+                    if !self.take_pending_operation(
+                        &account_id,
+                        &mut account,
+                        PendingOperationKind::DepositAndStake,
+                        deposited_amount.0,
+                        0,
+                    ) {
+                        return;
+                    }
                     account.stake_shares += num_shares;
-                    self.internal_save_account(&account_id, &account);
                     // update also contract total
-                    self.total_stake_shares += num_shares;
+                    *self.backend_totals_mut(&account).0 += num_shares;
+                    self.internal_save_account(&account_id, &account);
+                    Event::DepositAndStake {
+                        account_id,
+                        amount: deposited_amount,
+                        shares: num_shares.into(),
+                    }
+                    .emit();
                 } else {
                     // promise ok but no result? -- should not happen
-                    log!("UNEXPECTED ERROR: promise ok but no result!",);
+                    // the pending operation is kept, so it can be reconciled
+                    Event::OperationLeftPending {
+                        account_id,
+                        kind: PendingOperationKind::DepositAndStake,
+                        amount: deposited_amount,
+                        shares: 0.into(),
+                    }
+                    .emit();
                 }
             }
 
             PromiseResult::Failed => {
                 // stake at meta pool failed, ROLLBACK
-                self.clear_busy_flag(&account_id);
+                if !self.clear_pending_operation(
+                    &account_id,
+                    PendingOperationKind::DepositAndStake,
+                    deposited_amount.0,
+                    0,
+                ) {
+                    return;
+                }
                 // return NEARs to the lockup-account
-                Promise::new(account_id).transfer(deposited_amount.0);
+                Promise::new(account_id.clone()).transfer(deposited_amount.0);
+                Event::DepositAndStakeFailed {
+                    account_id,
+                    amount: deposited_amount,
+                }
+                .emit();
             }
         };
     }
@@ -154,7 +447,7 @@ impl StakingContract {
     /// The new total unstaked balance will be available for withdrawal in x epochs.
     pub fn unstake_all(&mut self) -> Promise {
         let account_id = env::predecessor_account_id();
-        assert_is_lockup_account(&account_id);
+        self.assert_can_unstake_and_withdraw(&account_id);
         let account = self.internal_get_account(&account_id);
         self.inner_unstake_shares(&account_id, account.stake_shares)
     }
@@ -165,15 +458,59 @@ impl StakingContract {
     /// given that the share price increases with staking rewards, it is possible that final amount
     /// withdrawn could be higher because of the inclusion of new staking rewards
     /// (the amount could only be higher, not lower)
+    /// If the attached gas allows, the stNEAR price is queried first so the amount is converted
+    /// to shares with the current price. Otherwise the cached price is used.
     pub fn unstake(&mut self, amount: U128) -> Promise {
         let account_id = env::predecessor_account_id();
-        assert_is_lockup_account(&account_id);
-        let amount: Balance = amount.into();
-        let shares = mul_div(amount, ONE_NEAR, self.share_near_price);
-        self.inner_unstake_shares(&account_id, shares)
+        self.assert_can_unstake_and_withdraw(&account_id);
+        let account = self.internal_get_account(&account_id);
+        let backend_gas = self.backend().extra_gas();
+        // the previous Meta Pool contract's price is not refreshed
+        let refresh_gas = self.gas_config.view.0
+            + self.gas_config.after_get_st_near_price_for_unstake()
+            + 2 * backend_gas;
+        if !self.is_on_previous_backend(&account) && has_gas_for(0, refresh_gas) {
+            // fail early, the account is not locked until the price arrives
+            self.assert_unstake_not_paused();
+            assert!(amount.0 > 0, "Unstaking amount should be positive");
+            assert!(!account.is_busy(), "The account is busy. Try again later");
+            self.backend().get_share_price().then(ext_self::after_get_st_near_price_for_unstake(
+                account_id,
+                amount,
+                //---
+                env::current_account_id(),
+                0,
+                Gas(self.gas_config.after_get_st_near_price_for_unstake() + backend_gas),
+            ))
+        } else {
+            self.inner_unstake_amount(&account_id, amount.0)
+        }
+    }
+    #[private]
+    // continues after previous fn
+    pub fn after_get_st_near_price_for_unstake(&mut self, account_id: AccountId, amount: U128) -> Promise {
+        // NOTE: no pending operation was set yet, so this callback can panic
+        // (e.g. the refreshed price requires more shares than the account has)
+        // in that case a rejected price (and the pause it may trigger) is not recorded, `ping` will
+        if let Some(st_near_price) = st_near_price_result() {
+            self.internal_update_share_near_price(st_near_price.0);
+        }
+        // if the query failed or the price was rejected continue with the cached price
+        self.inner_unstake_amount(&account_id, amount.0)
+    }
+
+    /// NEAR are converted to shares with the cached price
+    fn inner_unstake_amount(&mut self, account_id: &AccountId, amount: Balance) -> Promise {
+        let account = self.internal_get_account(account_id);
+        if !self.is_on_previous_backend(&account) {
+            self.assert_share_near_price_fresh();
+        }
+        let shares = mul_div(amount, ONE_NEAR, self.account_share_near_price(&account));
+        self.inner_unstake_shares(account_id, shares)
     }
 
     fn inner_unstake_shares(&mut self, account_id: &AccountId, num_shares: u128) -> Promise {
+        self.assert_unstake_not_paused();
         assert!(num_shares > 0, "Unstaking share amount should be positive");
 
         let account = self.internal_get_account(&account_id);
@@ -189,31 +526,30 @@ impl StakingContract {
             account.stake_shares
         );
 
+        let backend = self.backend_at(self.account_meta_pool_contract_id(&account));
+        assert_gas_for(self.gas_config.unstake_chain() + backend.unstake_extra_gas());
         // avoid re-entry
-        self.set_account_busy_flag_or_panic(&account_id);
-        // call meta pool
-        ext_metapool::unstake_from_lockup_shares(
-            account_id.to_string(),
-            num_shares.into(),
-            //---
-            self.meta_pool_contract_id.clone(),
+        self.set_pending_operation_or_panic(
+            account_id,
+            PendingOperationKind::Unstake,
             0,
-            Gas(META_POOL_UNSTAKE_SHARES_GAS),
-            )
-        .then(ext_self::after_unstake_shares(
+            num_shares,
+        );
+        // call meta pool
+        backend.unstake(account_id, num_shares).then(ext_self::after_unstake_shares(
             account_id.clone(),
             num_shares.into(),
             //---
             env::current_account_id(),
             0,
-            Gas(AFTER_UNSTAKE_SHARES_GAS),
+            Gas(self.gas_config.after_unstake.0),
         ))
     }
     #[private]
     // continues after previous fn
     pub fn after_unstake_shares(&mut self, account_id: AccountId, num_shares: U128) {
         // WARN: This is a callback after-cross-contract-call method
-        // busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        // pending operations (busy locks) must be cleared in the state, this method SHOULD NOT PANIC
         // SO DO NOT USE `#[callback]received_nears:U128` arguments, decode the return value manually
 
         // convert to u128
@@ -230,34 +566,58 @@ impl StakingContract {
                     let unstaked_nears = unstaked_nears.0;
                     // register the successful unstake share
                     let mut account = self.internal_get_account(&account_id);
-                    account.busy = false;
+                    if !self.take_pending_operation(
+                        &account_id,
+                        &mut account,
+                        PendingOperationKind::Unstake,
+                        0,
+                        num_shares,
+                    ) {
+                        return;
+                    }
                     account.stake_shares -= num_shares;
-                    account.unstaked_in_metapool += unstaked_nears;
-                    account.unstaked_available_epoch_height = unstaked_available_epoch_height.0;
-                    self.internal_save_account(&account_id, &account);
+                    account.add_unstaked(unstaked_nears, unstaked_available_epoch_height.0);
                     // update contract totals
-                    self.total_stake_shares -= num_shares;
-                    log!(
-                        "unstake shares at meta pool OK! account:{}, shares:{}, unstaked_nears:{}. Contract shares:{} ",
+                    let (total_stake_shares, total_unstaked_in_metapool) =
+                        self.backend_totals_mut(&account);
+                    *total_stake_shares -= num_shares;
+                    *total_unstaked_in_metapool += unstaked_nears;
+                    self.internal_save_account(&account_id, &account);
+                    Event::Unstake {
                         account_id,
-                        num_shares,
-                        unstaked_nears,
-                        self.total_stake_shares
-                    );
+                        shares: num_shares.into(),
+                        amount: unstaked_nears.into(),
+                        available_epoch_height: unstaked_available_epoch_height,
+                    }
+                    .emit();
                 } else {
                     // promise ok but no result? -- should not happen
-                    log!("UNEXPECTED ERROR: promise ok but no result!",);
+                    // the pending operation is kept, so it can be reconciled
+                    Event::OperationLeftPending {
+                        account_id,
+                        kind: PendingOperationKind::Unstake,
+                        amount: 0.into(),
+                        shares: num_shares.into(),
+                    }
+                    .emit();
                 }
             }
 
             PromiseResult::Failed => {
                 // unstake shares at meta pool failed!
-                self.clear_busy_flag(&account_id);
-                log!(
-                    "ERR: unstake shares at meta pool failed! account {}, shares {}",
+                if !self.clear_pending_operation(
+                    &account_id,
+                    PendingOperationKind::Unstake,
+                    0,
+                    num_shares,
+                ) {
+                    return;
+                }
+                Event::UnstakeFailed {
                     account_id,
-                    num_shares
-                );
+                    shares: num_shares.into(),
+                }
+                .emit();
             }
         };
     }
@@ -266,24 +626,57 @@ impl StakingContract {
     // == WITHDRAW ==
     // ==============
 
-    /// Withdraws the entire unstaked balance from the predecessor account.
-    /// It's only allowed if the `unstake` action was not performed in the four most recent epochs.
+    /// Withdraws the deposited balance and the matured unstaked balance from the predecessor account,
+    /// the tranches still waiting for their epoch are kept.
     pub fn withdraw_all(&mut self) -> Promise {
         let account_id = env::predecessor_account_id();
-        assert_is_lockup_account(&account_id);
+        self.assert_can_unstake_and_withdraw(&account_id);
         let account = self.internal_get_account(&account_id);
-        self.perform_withdraw(&account_id, account.unstaked_in_metapool)
+        let matured = account.matured_unstaked(env::epoch_height());
+        assert!(
+            matured > 0 || account.deposited_balance > 0 || account.unstaked_in_metapool == 0,
+            "The unstaked balance is not yet available due to unstaking delay"
+        );
+        self.internal_withdraw(&account_id, account.deposited_balance + matured)
     }
 
     /// Withdraws the non staked balance for given account.
-    /// It's only allowed if the `unstake` action was not performed in the four most recent epochs.
+    /// The deposited balance is returned first, right away, and the rest is withdrawn
+    /// from the matured tranches, see `get_account_withdrawable_balance`.
+    /// If both are needed the deposited part is sent only once the rest was withdrawn.
     pub fn withdraw(&mut self, amount: U128) -> Promise {
         let account_id = env::predecessor_account_id();
-        assert_is_lockup_account(&account_id);
-        self.perform_withdraw(&account_id, amount.into())
+        self.assert_can_unstake_and_withdraw(&account_id);
+        self.internal_withdraw(&account_id, amount.into())
     }
 
-    fn perform_withdraw(&mut self, account_id: &AccountId, amount: Balance) -> Promise {
+    fn internal_withdraw(&mut self, account_id: &AccountId, amount: Balance) -> Promise {
+        let deposited = std::cmp::min(amount, self.internal_get_account(account_id).deposited_balance);
+        if deposited == amount {
+            self.perform_withdraw_deposited(account_id, amount)
+        } else {
+            // all or nothing: the deposited part is paid by the callback, if the rest was withdrawn
+            self.perform_withdraw(account_id, amount - deposited, deposited)
+        }
+    }
+
+    /// Returns deposited balance held here, there's no unstaking delay
+    fn perform_withdraw_deposited(&mut self, account_id: &AccountId, amount: Balance) -> Promise {
+        let mut account = self.internal_get_account(account_id);
+        // an in-flight withdrawal may be holding it
+        assert!(!account.is_busy(), "The account is busy. Try again later");
+        account.deposited_balance -= amount;
+        self.internal_save_account(account_id, &account);
+        Event::Withdraw {
+            account_id: account_id.clone(),
+            amount: amount.into(),
+        }
+        .emit();
+        Promise::new(account_id.clone()).transfer(amount)
+    }
+
+    /// Withdraws `amount` from the matured tranches, then sends `deposited` of the deposited balance
+    fn perform_withdraw(&mut self, account_id: &AccountId, amount: Balance, deposited: Balance) -> Promise {
         assert!(amount > 0, "Withdrawal amount should be positive");
         let account = self.internal_get_account(&account_id);
         // the user has enough balance?
@@ -295,63 +688,82 @@ impl StakingContract {
         // Note: the reference contract is near-core/staking-contract from the NEAR foundation.
         // In that contract, asking for unstake locks all funds, including any funds deposited but not staked yet.
         // https://github.com/near/core-contracts/blob/3f3170fce91ff4d8c6ee9d15683f2d4dfe1275cf/staking-pool/src/internal.rs#L42
-        // Here we need to replicate the same mechanics: reject withdrawals if env::epoch_height() < unstaked_available_epoch_height
-        // even if there are funds in account.unstaked, in order to emulated the expected behavior set by near-core/staking-contract
+        // Here each unstake keeps its own maturity epoch, so the funds unstaked earlier are not locked by a later unstake.
+        // The lockup contract checks `is_account_unstaked_balance_available` (every tranche matured) before withdrawing everything.
 
-        // make sure the wait period is over
+        // make sure the wait period is over for the amount
         assert!(
-            account.unstaked_available_epoch_height <= env::epoch_height(),
+            account.matured_unstaked(env::epoch_height()) >= amount,
             "The unstaked balance is not yet available due to unstaking delay"
         );
 
+        let backend = self.backend_at(self.account_meta_pool_contract_id(&account));
+        assert_gas_for(self.gas_config.withdraw_chain() + backend.extra_gas());
         // avoiding re-entry
-        self.set_account_busy_flag_or_panic(&account_id);
-        // call metapool. The NEAR will be sent directly to the lockup account
-        ext_metapool::withdraw_to_lockup(
-            account_id.to_string(),
-            amount.into(),
-            //--
-            self.meta_pool_contract_id.clone(),
-            0,
-            Gas(META_POOL_WITHDRAW_GAS),
-            )
-        .then(ext_self::after_metapool_withdraw_to_lockup(
+        self.set_pending_operation_or_panic(account_id, PendingOperationKind::Withdraw, amount, 0);
+        // call metapool. The NEAR will be sent to the lockup account
+        backend.withdraw(account_id, amount).then(ext_self::after_metapool_withdraw_to_lockup(
             account_id.clone(),
             amount.into(),
+            deposited.into(),
             //--
             env::current_account_id(),
             0,
-            Gas(AFTER_WITHDRAW_GAS),
+            Gas(self.gas_config.after_withdraw.0),
         ))
     }
     #[private]
     // continues after previous fn
-    pub fn after_metapool_withdraw_to_lockup(&mut self, account_id: AccountId, amount: U128) {
+    pub fn after_metapool_withdraw_to_lockup(&mut self, account_id: AccountId, amount: U128, deposited: U128) {
         // WARN: This is a callback after-cross-contract-call method
-        // busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        // pending operations (busy locks) must be cleared in the state, this method SHOULD NOT PANIC
         let amount = amount.0;
         if is_promise_success() {
             // withdraw success
-            log!(
-                "withdraw from meta pool to {} for {} yNEAR succeeded",
-                account_id,
-                amount,
-            );
             // the amount was sent by meta-pool to the lockup account
             let mut account = self.internal_get_account(&account_id);
-            account.busy = false;
-            account.unstaked_in_metapool = account.unstaked_in_metapool.saturating_sub(amount);
+            if !self.take_pending_operation(
+                &account_id,
+                &mut account,
+                PendingOperationKind::Withdraw,
+                amount,
+                0,
+            ) {
+                return;
+            }
+            account.remove_unstaked(amount);
+            let total_unstaked_in_metapool = self.backend_totals_mut(&account).1;
+            *total_unstaked_in_metapool = total_unstaked_in_metapool.saturating_sub(amount);
+            // the deposited part, held here while the account was busy
+            let deposited = std::cmp::min(deposited.0, account.deposited_balance);
+            account.deposited_balance -= deposited;
             // save account
             self.internal_save_account(&account_id, &account);
+            if deposited > 0 {
+                Promise::new(account_id.clone()).transfer(deposited);
+            }
+            Event::Withdraw {
+                account_id,
+                amount: (amount + deposited).into(),
+            }
+            .emit();
         } else {
             // failed!
-            self.clear_busy_flag(&account_id);
+            if !self.clear_pending_operation(
+                &account_id,
+                PendingOperationKind::Withdraw,
+                amount,
+                0,
+            ) {
+                return;
+            }
             // withdraw at meta pool failed, but we can not panic here, just log
-            log!(
-                "ERROR! @{} asking for METAPOOL withdraw {} FAILED",
+            // the deposited part was not sent either
+            Event::WithdrawFailed {
                 account_id,
-                amount,
-            );
+                amount: (amount + deposited.0).into(),
+            }
+            .emit();
         }
     }
 }
@@ -0,0 +1,363 @@
+use near_sdk::json_types::U64;
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::{is_promise_success, PromiseResult};
+
+use crate::gas::GasConfig;
+use crate::ping::MetaPoolContractState;
+use crate::utils::{mul_div, TGAS};
+use crate::views::RewardFeeFraction;
+use crate::*;
+
+/// gas for the callbacks adapting the staking pool results
+pub const STAKING_POOL_CALLBACK_GAS: u64 = 5 * TGAS;
+/// core-contracts staking pools release unstaked NEAR after 4 epochs
+pub const NUM_EPOCHS_TO_UNLOCK: EpochHeight = 4;
+
+/// Interface for a core-contracts staking pool
+#[ext_contract(ext_staking_pool)]
+trait StakingPool {
+    fn deposit_and_stake(&mut self);
+    fn stake(&mut self, amount: U128);
+    fn unstake(&mut self, amount: U128);
+    fn withdraw(&mut self, amount: U128);
+    fn get_account_staked_balance(&self, account_id: AccountId) -> U128;
+    fn get_reward_fee_fraction(&self) -> RewardFeeFraction;
+}
+/// Interface for the contract itself.
+#[ext_contract(ext_self)]
+pub trait SelfContract {
+    fn on_staking_pool_stake_start(&mut self, amount: U128, restake: bool) -> Promise;
+    fn on_staking_pool_staked(&mut self, amount: U128, restake: bool, staked_before: U128) -> Promise;
+    fn on_staking_pool_stake(&mut self, amount: U128, staked_before: U128) -> U128;
+    fn on_staking_pool_restake(&mut self, amount: U128, staked_before: U128) -> (U128, U128);
+    fn on_staking_pool_unstake_start(&mut self, shares: U128) -> Promise;
+    fn on_staking_pool_unstake(&mut self, amount: U128) -> (U128, U64);
+    fn on_staking_pool_withdraw(&mut self, account_id: AccountId, amount: U128) -> Promise;
+    fn on_staking_pool_price(&self) -> U128;
+    fn on_staking_pool_state(&self) -> MetaPoolContractState;
+}
+
+/// A staking pool, with this contract as the only delegator.
+/// Shares are this contract's own: a share is worth its staked balance at the pool / `total_stake_shares`.
+/// A stake reads the staked balance at the pool before and after it, and mints shares for the change.
+/// An unstake reads it first, and unstakes the shares' part of it.
+/// Operations are serialized (see `set_pending_operation_or_panic`), so the change is the stake's only.
+/// NOTE: the pool keeps a single unlock epoch for all the NEAR unstaked by this contract,
+/// so an unstake delays the withdrawals of the NEAR unstaked before it
+pub struct StakingPoolBackend {
+    pub contract_id: AccountId,
+    pub gas_config: GasConfig,
+}
+
+impl StakingPoolBackend {
+    fn callback_gas(&self) -> Gas {
+        Gas(STAKING_POOL_CALLBACK_GAS)
+    }
+
+    /// gas of `on_staking_pool_stake_start`: the pool call and the callbacks after it
+    fn stake_start_gas(&self) -> u64 {
+        self.gas_config.deposit_and_stake.0 + STAKING_POOL_CALLBACK_GAS + staked_gas(&self.gas_config)
+    }
+
+    /// Reads this contract's staked balance at the pool, then stakes `amount`
+    /// (attached, or from the unstaked balance at the pool if `restake`)
+    fn stake_measured(&self, amount: Balance, restake: bool) -> Promise {
+        ext_staking_pool::get_account_staked_balance(
+            env::current_account_id(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        )
+        .then(ext_self::on_staking_pool_stake_start(
+            amount.into(),
+            restake,
+            //---
+            env::current_account_id(),
+            if restake { 0 } else { amount },
+            Gas(self.stake_start_gas()),
+        ))
+    }
+}
+
+impl StakingBackend for StakingPoolBackend {
+    fn stake(&self, _account_id: &AccountId, amount: Balance) -> Promise {
+        self.stake_measured(amount, false)
+    }
+
+    fn unstake(&self, _account_id: &AccountId, shares: NumStakeShares) -> Promise {
+        // the cached price misses the rewards since the last ping, the shares are valued at the pool
+        ext_staking_pool::get_account_staked_balance(
+            env::current_account_id(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        )
+        .then(ext_self::on_staking_pool_unstake_start(
+            shares.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(self.gas_config.unstake.0 + 2 * STAKING_POOL_CALLBACK_GAS),
+        ))
+    }
+
+    fn withdraw(&self, account_id: &AccountId, amount: Balance) -> Promise {
+        // the pool sends the NEAR to this contract, it's forwarded to the account
+        ext_staking_pool::withdraw(
+            amount.into(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.withdraw.0),
+        )
+        .then(ext_self::on_staking_pool_withdraw(
+            account_id.clone(),
+            amount.into(),
+            //---
+            env::current_account_id(),
+            0,
+            self.callback_gas(),
+        ))
+    }
+
+    fn restake(&self, _account_id: &AccountId, amount: Balance) -> Promise {
+        // the pool stakes again from this contract's unstaked balance, matured or not
+        self.stake_measured(amount, true)
+    }
+
+    fn can_restake_unmatured(&self) -> bool {
+        true
+    }
+
+    fn restake_gas(&self) -> u64 {
+        self.gas_config.view.0 + self.stake_start_gas()
+    }
+
+    fn get_share_price(&self) -> Promise {
+        ext_staking_pool::get_account_staked_balance(
+            env::current_account_id(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        )
+        .then(ext_self::on_staking_pool_price(
+            env::current_account_id(),
+            0,
+            self.callback_gas(),
+        ))
+    }
+
+    fn get_contract_state(&self) -> Promise {
+        ext_staking_pool::get_account_staked_balance(
+            env::current_account_id(),
+            //---
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        )
+        .and(ext_staking_pool::get_reward_fee_fraction(
+            self.contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        ))
+        .then(ext_self::on_staking_pool_state(
+            env::current_account_id(),
+            0,
+            self.callback_gas(),
+        ))
+    }
+
+    fn get_account_info(&self) -> Option<Promise> {
+        None
+    }
+
+    fn extra_gas(&self) -> u64 {
+        STAKING_POOL_CALLBACK_GAS
+    }
+
+    fn stake_extra_gas(&self) -> u64 {
+        // staked balance queries before and after, and the callbacks around the pool call
+        2 * self.gas_config.view.0 + 3 * STAKING_POOL_CALLBACK_GAS
+    }
+
+    fn unstake_extra_gas(&self) -> u64 {
+        // staked balance query, and the callbacks around the pool call
+        self.gas_config.view.0 + 2 * STAKING_POOL_CALLBACK_GAS
+    }
+}
+
+/// gas of `on_staking_pool_staked`: the staked balance query and the last callback
+fn staked_gas(gas_config: &GasConfig) -> u64 {
+    gas_config.view.0 + 2 * STAKING_POOL_CALLBACK_GAS
+}
+
+/// decodes the result of a staking pool call, panics if it failed
+fn staking_pool_result<T: DeserializeOwned>(index: u64) -> T {
+    match env::promise_result(index) {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<T>(&value)
+            .expect("Invalid staking pool result"),
+        _ => panic!("The staking pool call failed"),
+    }
+}
+
+impl StakingContract {
+    /// NEAR value of a share given this contract's staked balance at the pool.
+    /// Not known while an operation is in flight: the pool balance moved, but not the shares yet
+    fn staking_pool_share_price(&self, staked_balance: Balance) -> Balance {
+        assert!(
+            self.in_flight_operations == 0,
+            "The share price can't be computed while an operation is in flight"
+        );
+        if self.total_stake_shares == 0 {
+            self.share_near_price
+        } else {
+            mul_div(staked_balance, ONE_E24, self.total_stake_shares)
+        }
+    }
+
+    /// Shares for the NEAR a stake added to this contract's staked balance at the pool.
+    /// The rest of the balance after the stake is what the existing shares are worth,
+    /// so rewards received meanwhile are not minted for the stake
+    fn staking_pool_minted_shares(&self, amount: Balance, staked_before: Balance) -> NumStakeShares {
+        let staked_after: U128 = staking_pool_result(0);
+        let staked = std::cmp::min(staked_after.0.saturating_sub(staked_before), amount);
+        assert!(staked > 0, "The staking pool staked balance did not increase");
+        let others = staked_after.0 - staked;
+        if self.total_stake_shares == 0 || others == 0 {
+            mul_div(staked, ONE_E24, self.share_near_price)
+        } else {
+            mul_div(staked, self.total_stake_shares, others)
+        }
+    }
+}
+
+///***************************/
+///* Staking pool callbacks  */
+///***************************/
+// These callbacks hold no lock, they may panic: the calling callback sees the failure
+#[near_bindgen]
+impl StakingContract {
+    /// Receives the staked balance before the stake, and sends the stake to the pool.
+    /// If it panics the attached deposit comes back to this contract
+    #[payable]
+    #[private]
+    pub fn on_staking_pool_stake_start(&mut self, amount: U128, restake: bool) -> Promise {
+        let staked_before: U128 = staking_pool_result(0);
+        let pool_gas = Gas(self.gas_config.deposit_and_stake.0);
+        if restake {
+            ext_staking_pool::stake(amount, self.meta_pool_contract_id.clone(), 0, pool_gas)
+        } else {
+            ext_staking_pool::deposit_and_stake(self.meta_pool_contract_id.clone(), amount.0, pool_gas)
+        }
+        .then(ext_self::on_staking_pool_staked(
+            amount,
+            restake,
+            staked_before,
+            //---
+            env::current_account_id(),
+            0,
+            Gas(staked_gas(&self.gas_config)),
+        ))
+    }
+
+    /// Once the pool staked, reads the staked balance after the stake
+    #[private]
+    pub fn on_staking_pool_staked(&mut self, amount: U128, restake: bool, staked_before: U128) -> Promise {
+        // the deposit is returned to this contract if the pool fails, nothing is released on a restake
+        assert!(is_promise_success(), "The staking pool stake failed");
+        let query = ext_staking_pool::get_account_staked_balance(
+            env::current_account_id(),
+            //---
+            self.meta_pool_contract_id.clone(),
+            0,
+            Gas(self.gas_config.view.0),
+        );
+        if restake {
+            query.then(ext_self::on_staking_pool_restake(
+                amount,
+                staked_before,
+                //---
+                env::current_account_id(),
+                0,
+                Gas(STAKING_POOL_CALLBACK_GAS),
+            ))
+        } else {
+            query.then(ext_self::on_staking_pool_stake(
+                amount,
+                staked_before,
+                //---
+                env::current_account_id(),
+                0,
+                Gas(STAKING_POOL_CALLBACK_GAS),
+            ))
+        }
+    }
+
+    #[private]
+    pub fn on_staking_pool_stake(&mut self, amount: U128, staked_before: U128) -> U128 {
+        self.staking_pool_minted_shares(amount.0, staked_before.0).into()
+    }
+
+    #[private]
+    pub fn on_staking_pool_restake(&mut self, amount: U128, staked_before: U128) -> (U128, U128) {
+        (self.staking_pool_minted_shares(amount.0, staked_before.0).into(), 0.into())
+    }
+
+    /// Receives the staked balance, and unstakes what `shares` are worth of it.
+    /// The shares are still in `total_stake_shares`, so the last ones unstake everything left
+    #[private]
+    pub fn on_staking_pool_unstake_start(&mut self, shares: U128) -> Promise {
+        let staked_balance: U128 = staking_pool_result(0);
+        let amount = mul_div(staked_balance.0, shares.0, self.total_stake_shares);
+        assert!(amount > 0, "Nothing to unstake at the staking pool");
+        ext_staking_pool::unstake(
+            amount.into(),
+            //---
+            self.meta_pool_contract_id.clone(),
+            0,
+            Gas(self.gas_config.unstake.0),
+        )
+        .then(ext_self::on_staking_pool_unstake(
+            amount.into(),
+            //---
+            env::current_account_id(),
+            0,
+            Gas(STAKING_POOL_CALLBACK_GAS),
+        ))
+    }
+
+    #[private]
+    pub fn on_staking_pool_unstake(&mut self, amount: U128) -> (U128, U64) {
+        assert!(is_promise_success(), "The staking pool unstake failed");
+        (amount, (env::epoch_height() + NUM_EPOCHS_TO_UNLOCK).into())
+    }
+
+    #[private]
+    pub fn on_staking_pool_withdraw(&mut self, account_id: AccountId, amount: U128) -> Promise {
+        assert!(is_promise_success(), "The staking pool withdraw failed");
+        Promise::new(account_id).transfer(amount.0)
+    }
+
+    #[private]
+    pub fn on_staking_pool_price(&self) -> U128 {
+        let staked_balance: U128 = staking_pool_result(0);
+        self.staking_pool_share_price(staked_balance.0).into()
+    }
+
+    #[private]
+    pub fn on_staking_pool_state(&self) -> MetaPoolContractState {
+        let staked_balance: U128 = staking_pool_result(0);
+        let fee: RewardFeeFraction = staking_pool_result(1);
+        assert!(fee.denominator > 0, "Invalid staking pool reward fee");
+        MetaPoolContractState {
+            st_near_price: self.staking_pool_share_price(staked_balance.0).into(),
+            operator_rewards_fee_basis_points: std::cmp::min(
+                fee.numerator as u64 * 10_000 / fee.denominator as u64,
+                10_000,
+            ) as u16,
+        }
+    }
+}
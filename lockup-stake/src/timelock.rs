@@ -0,0 +1,169 @@
+use near_sdk::json_types::U64;
+use near_sdk::{assert_one_yocto, env::is_valid_account_id};
+
+use crate::*;
+
+/// Default delay between queueing an admin action and being able to execute it (~2 days)
+pub const DEFAULT_ADMIN_ACTION_DELAY_EPOCHS: u64 = 4;
+/// lockup owners always get at least an epoch to unstake before a change lands
+pub const MIN_ADMIN_ACTION_DELAY_EPOCHS: u64 = 1;
+/// the owner can't lock itself out with a huge delay
+pub const MAX_ADMIN_ACTION_DELAY_EPOCHS: u64 = 30;
+/// keeps `get_admin_actions` unpaginated
+pub const MAX_QUEUED_ADMIN_ACTIONS: u64 = 10;
+
+/// Owner actions that take effect only after `admin_action_delay_epochs`,
+/// so lockup owners can exit before a change they disagree with lands
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AdminAction {
+    /// proposes a new owner, who still has to call `accept_ownership`
+    ProposeOwner { owner_id: AccountId },
+    /// sends new deposits to another Meta Pool contract and pauses deposits.
+    /// Accounts with funds at the current one can still unstake and withdraw there
+    SetMetaPoolContractId { meta_pool_contract_id: AccountId },
+    SetAdminActionDelay { epochs: U64 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedAdminAction {
+    pub id: U64,
+    pub action: AdminAction,
+    pub queued_epoch_height: U64,
+    /// `execute_admin_action` is accepted from this epoch on
+    pub executable_epoch_height: U64,
+}
+
+impl AdminAction {
+    fn assert_valid(&self) {
+        match self {
+            AdminAction::ProposeOwner { owner_id } => {
+                assert!(is_valid_account_id(owner_id.as_bytes()), "Invalid owner account id")
+            }
+            AdminAction::SetMetaPoolContractId { meta_pool_contract_id } => assert!(
+                is_valid_account_id(meta_pool_contract_id.as_bytes()),
+                "Invalid Meta Pool account id"
+            ),
+            AdminAction::SetAdminActionDelay { epochs } => assert!(
+                epochs.0 >= MIN_ADMIN_ACTION_DELAY_EPOCHS && epochs.0 <= MAX_ADMIN_ACTION_DELAY_EPOCHS,
+                "The delay must be between {} and {} epochs",
+                MIN_ADMIN_ACTION_DELAY_EPOCHS,
+                MAX_ADMIN_ACTION_DELAY_EPOCHS
+            ),
+        }
+    }
+}
+
+impl StakingContract {
+    /// Queues the action, the caller must be the owner. Returns its id
+    pub(crate) fn internal_queue_admin_action(&mut self, action: AdminAction) -> U64 {
+        action.assert_valid();
+        assert!(
+            self.admin_actions.len() < MAX_QUEUED_ADMIN_ACTIONS,
+            "At most {} queued admin actions",
+            MAX_QUEUED_ADMIN_ACTIONS
+        );
+        let id = self.next_admin_action_id;
+        self.next_admin_action_id += 1;
+        let queued = QueuedAdminAction {
+            id: id.into(),
+            action,
+            queued_epoch_height: env::epoch_height().into(),
+            executable_epoch_height: (env::epoch_height() + self.config.admin_action_delay_epochs.0)
+                .into(),
+        };
+        self.admin_actions.insert(&id, &queued);
+        Event::AdminActionQueued {
+            id: queued.id,
+            action: queued.action,
+            executable_epoch_height: queued.executable_epoch_height,
+        }
+        .emit();
+        id.into()
+    }
+
+    fn internal_apply_admin_action(&mut self, action: &AdminAction) {
+        match action {
+            AdminAction::ProposeOwner { owner_id } => {
+                self.pending_owner_id = Some(owner_id.clone());
+                Event::OwnerProposed {
+                    owner_id: self.owner_id.clone(),
+                    proposed_owner_id: owner_id.clone(),
+                }
+                .emit();
+            }
+            AdminAction::SetMetaPoolContractId { meta_pool_contract_id } => {
+                self.internal_switch_backend(meta_pool_contract_id.clone())
+            }
+            AdminAction::SetAdminActionDelay { epochs } => {
+                self.config.admin_action_delay_epochs = *epochs;
+                Event::ConfigChanged {
+                    config: self.config.clone(),
+                }
+                .emit();
+            }
+        }
+    }
+}
+
+///*************************/
+///* Admin actions methods */
+///*************************/
+#[near_bindgen]
+impl StakingContract {
+    /// Queues an owner action, it can be executed after `get_config().admin_action_delay_epochs`.
+    /// Returns the action id
+    #[payable]
+    pub fn queue_admin_action(&mut self, action: AdminAction) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_queue_admin_action(action)
+    }
+
+    /// Executes a queued action once its delay has passed. Must be called by the owner or an operator,
+    /// queueing and cancelling are owner only.
+    #[payable]
+    pub fn execute_admin_action(&mut self, id: U64) {
+        assert_one_yocto();
+        self.assert_owner_or_role(Role::Operator);
+        let queued = self.admin_actions.get(&id.0).expect("Admin action not found");
+        assert!(
+            env::epoch_height() >= queued.executable_epoch_height.0,
+            "The admin action can't be executed until epoch {}",
+            queued.executable_epoch_height.0
+        );
+        self.admin_actions.remove(&id.0);
+        self.internal_apply_admin_action(&queued.action);
+        Event::AdminActionExecuted {
+            id,
+            action: queued.action,
+        }
+        .emit();
+    }
+
+    /// Removes a queued action without executing it. Must be called by the owner.
+    #[payable]
+    pub fn cancel_admin_action(&mut self, id: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        let queued = self.admin_actions.remove(&id.0).expect("Admin action not found");
+        Event::AdminActionCancelled {
+            id,
+            action: queued.action,
+        }
+        .emit();
+    }
+
+    pub fn get_admin_action(&self, id: U64) -> Option<QueuedAdminAction> {
+        self.admin_actions.get(&id.0)
+    }
+
+    /// Returns the queued actions, oldest first
+    pub fn get_admin_actions(&self) -> Vec<QueuedAdminAction> {
+        let mut actions = self.admin_actions.values_as_vector().to_vec();
+        actions.sort_by_key(|queued| queued.id.0);
+        actions
+    }
+}
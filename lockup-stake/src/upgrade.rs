@@ -0,0 +1,101 @@
+use near_sdk::json_types::Base58CryptoHash;
+
+use crate::gas::assert_gas_for;
+use crate::utils::TGAS;
+use crate::*;
+
+/// Storage key for the staged wasm, kept out of the contract struct
+/// so it's not read/written on every call
+const STAGED_CODE_KEY: &[u8] = b"staged_code";
+
+/// Gas for the `migrate` call chained after deploying the staged code.
+/// `migrate` reads and rewrites the whole contract state, so it's kept well above its measured cost
+pub const MIGRATE_GAS: u64 = 100 * TGAS;
+
+///*******************/
+///* Upgrade methods */
+///*******************/
+#[near_bindgen]
+impl StakingContract {
+    /// Stores a new contract code, to be deployed later with `deploy_staged_upgrade`.
+    /// The call input is the raw wasm, NOT json.
+    /// The attached deposit must cover the storage used by the staged code.
+    /// Returns the sha256 of the staged code (the code_hash the account will have after the upgrade)
+    #[payable]
+    pub fn stage_upgrade(&mut self) -> Base58CryptoHash {
+        self.assert_owner();
+        let code = env::input().expect("the staged code must be the call input");
+        assert!(!code.is_empty(), "the staged code is empty");
+
+        let storage_before = env::storage_usage();
+        env::storage_write(STAGED_CODE_KEY, &code);
+        let hash = env::sha256_array(&code);
+        self.staged_code_hash = Some(hash);
+        let storage_after = env::storage_usage();
+
+        let storage_cost =
+            storage_after.saturating_sub(storage_before) as Balance * env::storage_byte_cost();
+        assert!(
+            env::attached_deposit() >= storage_cost,
+            "The attached deposit should cover the staged code storage: {} yNEAR",
+            storage_cost
+        );
+        Event::UpgradeStaged {
+            code_hash: hash.into(),
+        }
+        .emit();
+        hash.into()
+    }
+
+    /// Removes the staged code, releasing its storage.
+    /// The storage cost paid in `stage_upgrade` is returned to the owner
+    #[payable]
+    pub fn cancel_staged_upgrade(&mut self) -> Promise {
+        self.assert_owner();
+        assert_one_yocto();
+        let code_hash = self.staged_code_hash.expect("no staged upgrade");
+        let storage_before = env::storage_usage();
+        env::storage_remove(STAGED_CODE_KEY);
+        self.staged_code_hash = None;
+        let freed_bytes = storage_before.saturating_sub(env::storage_usage());
+
+        Event::UpgradeCancelled {
+            code_hash: code_hash.into(),
+        }
+        .emit();
+        Promise::new(env::predecessor_account_id())
+            .transfer(freed_bytes as Balance * env::storage_byte_cost())
+    }
+
+    /// Deploys the staged code on this account and calls `migrate` on the new code.
+    /// `expected_hash` must match the staged code hash, as shown by `get_staged_upgrade_hash`.
+    /// The staged code is consumed: if `migrate` fails the deploy is reverted and the code must be staged again.
+    #[payable]
+    pub fn deploy_staged_upgrade(&mut self, expected_hash: Base58CryptoHash) -> Promise {
+        self.assert_owner();
+        assert_one_yocto();
+        let staged_hash = self.staged_code_hash.expect("no staged upgrade");
+        assert!(
+            staged_hash == CryptoHash::from(expected_hash),
+            "The staged code hash does not match the expected hash"
+        );
+        // checked before the staged code is consumed
+        assert_gas_for(MIGRATE_GAS);
+        let code = env::storage_read(STAGED_CODE_KEY).expect("staged code is missing");
+        env::storage_remove(STAGED_CODE_KEY);
+        self.staged_code_hash = None;
+
+        Event::UpgradeDeployed {
+            code_hash: expected_hash,
+        }
+        .emit();
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".into(), b"{}".to_vec(), 0, Gas(MIGRATE_GAS))
+    }
+
+    /// Returns the sha256 of the staged code, if any
+    pub fn get_staged_upgrade_hash(&self) -> Option<Base58CryptoHash> {
+        self.staged_code_hash.map(|hash| hash.into())
+    }
+}
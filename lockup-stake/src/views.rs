@@ -1,4 +1,4 @@
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::{env, AccountId};
 
 use crate::utils::mul_div;
@@ -16,12 +16,30 @@ pub struct RewardFeeFraction {
 #[serde(crate = "near_sdk::serde")]
 pub struct HumanReadableAccount {
     pub account_id: AccountId,
-    /// The unstaked balance that can be withdrawn or staked.
+    /// The unstaked balance that can be withdrawn or staked, the deposited balance included.
     pub unstaked_balance: U128,
     /// The amount balance staked at the current "stake" share price.
     pub staked_balance: U128,
     /// Whether the unstaked balance is available for withdrawal now.
     pub can_withdraw: bool,
+    /// when the stNEAR price `staked_balance` is computed with was received (0 if never).
+    /// It's the price of the previous Meta Pool contract for accounts still staked there
+    pub st_near_price_epoch_height: U64,
+    /// epochs since that price was received
+    pub st_near_price_age_epochs: U64,
+}
+
+/// The cached stNEAR price and how old it is.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StNearPriceInfo {
+    /// NEARs per stNEAR, with 24 decimals
+    pub st_near_price: U128,
+    /// when the price was received from Meta Pool (0 if never)
+    pub epoch_height: U64,
+    pub timestamp: U64,
+    /// epochs since the price was received
+    pub age_epochs: U64,
 }
 
 #[near_bindgen]
@@ -41,7 +59,8 @@ impl StakingContract {
     }
 
     /// Returns the staked balance of the given account.
-    /// NOTE: This is computed from the amount of "stake" shares the given account has and the
+    /// NOTE: The stNEAR price used is the one cached on `ping`, see `get_st_near_price_info`.
+    /// This is computed from the amount of "stake" shares the given account has and the
     /// current amount of total staked balance and total stake shares on the account.
     pub fn get_account_staked_balance(&self, account_id: AccountId) -> U128 {
         self.get_account(account_id).staked_balance
@@ -59,18 +78,69 @@ impl StakingContract {
         account.stake_shares.into()
     }
 
+    /// Returns the number of accounts with a pending operation (`Unknown` ones not included)
+    pub fn get_in_flight_operations(&self) -> U64 {
+        self.in_flight_operations.into()
+    }
+
+    /// Returns the cross-contract operation the account is waiting for, if it's busy.
+    pub fn get_account_pending_operation(
+        &self,
+        account_id: AccountId,
+    ) -> Option<HumanReadablePendingOperation> {
+        self.internal_get_account(&account_id)
+            .pending_operation
+            .map(|op| op.into())
+    }
+
     /// Returns `true` if the given account can withdraw tokens in the current epoch.
+    /// As the lockup contract expects, that's the whole unstaked balance: every tranche matured.
     pub fn is_account_unstaked_balance_available(&self, account_id: AccountId) -> bool {
         self.get_account(account_id).can_withdraw
     }
 
-    /// Returns the total staking balance.
+    /// Returns the unstaked balance the account can withdraw in the current epoch
+    /// (its deposited balance and matured tranches).
+    pub fn get_account_withdrawable_balance(&self, account_id: AccountId) -> U128 {
+        let account = self.internal_get_account(&account_id);
+        (account.matured_unstaked(env::epoch_height()) + account.deposited_balance).into()
+    }
+
+    /// Returns the balance received with `deposit` and held by this contract, not staked yet.
+    pub fn get_account_deposited_balance(&self, account_id: AccountId) -> U128 {
+        self.internal_get_account(&account_id).deposited_balance.into()
+    }
+
+    /// Returns the account's unstaked balance by maturity epoch, earliest first.
+    pub fn get_account_unstake_tranches(
+        &self,
+        account_id: AccountId,
+    ) -> Vec<HumanReadableUnstakeTranche> {
+        self.internal_get_account(&account_id)
+            .unstake_tranches
+            .into_iter()
+            .map(|tranche| tranche.into())
+            .collect()
+    }
+
+    /// Returns the total staking balance, including the previous Meta Pool contract if any.
     pub fn get_total_staked_balance(&self) -> U128 {
         // return shares * price
-        mul_div(self.total_stake_shares, self.share_near_price, ONE_E24).into()
+        self.internal_total_staked_balance().into()
     }
 
-    /// Returns total_stake_shares
+    /// Returns the stNEAR price used by the balance views and `unstake(amount)`, and its age.
+    /// Staked balances are only as fresh as this price, call `ping` to refresh it.
+    pub fn get_st_near_price_info(&self) -> StNearPriceInfo {
+        StNearPriceInfo {
+            st_near_price: self.share_near_price.into(),
+            epoch_height: self.share_near_price_epoch_height.into(),
+            timestamp: self.share_near_price_timestamp.into(),
+            age_epochs: self.share_near_price_age_epochs().into(),
+        }
+    }
+
+    /// Returns total_stake_shares (at the current Meta Pool contract)
     pub fn get_total_stake_shares(&self) -> U128 {
         self.total_stake_shares.into()
     }
@@ -83,33 +153,57 @@ impl StakingContract {
         };
     }
 
-    /// kept for compatibility with core-contracts/staking-pool
+    /// Returns `true` if new deposits are paused (core-contracts/staking-pool compatible)
     pub fn is_staking_paused(&self) -> bool {
-        false
+        self.staking_paused
+    }
+
+    /// Returns `true` if unstaking is paused too. Withdrawals are never paused.
+    pub fn is_unstake_paused(&self) -> bool {
+        self.unstake_paused
     }
 
     /// Returns human readable representation of the account for the given account ID.
     pub fn get_account(&self, account_id: AccountId) -> HumanReadableAccount {
         let account = self.internal_get_account(&account_id);
+        let st_near_price_epoch_height = self.account_share_near_price_epoch_height(&account);
         HumanReadableAccount {
             account_id,
-            unstaked_balance: account.unstaked_in_metapool.into(),
-            staked_balance: mul_div(account.stake_shares, self.share_near_price, ONE_E24).into(),
+            unstaked_balance: (account.unstaked_in_metapool + account.deposited_balance).into(),
+            staked_balance: mul_div(
+                account.stake_shares,
+                self.account_share_near_price(&account),
+                ONE_E24,
+            )
+            .into(),
             can_withdraw: account.unstaked_available_epoch_height <= env::epoch_height(),
+            st_near_price_epoch_height: st_near_price_epoch_height.into(),
+            st_near_price_age_epochs: env::epoch_height()
+                .saturating_sub(st_near_price_epoch_height)
+                .into(),
         }
     }
 
     /// Returns the number of accounts that have positive balance on this staking pool.
     pub fn get_number_of_accounts(&self) -> u64 {
-        self.accounts.len()
+        self.accounts.len() + self.legacy_accounts.len()
     }
 
     /// Returns the list of accounts
+    /// (accounts pending migration are listed after the migrated ones)
     pub fn get_accounts(&self, from_index: u64, limit: u64) -> Vec<HumanReadableAccount> {
         let keys = self.accounts.keys_as_vector();
-
-        (from_index..std::cmp::min(from_index + limit, keys.len()))
-            .map(|index| self.get_account(keys.get(index).unwrap()))
+        let legacy_keys = self.legacy_accounts.keys_as_vector();
+
+        (from_index..std::cmp::min(from_index + limit, keys.len() + legacy_keys.len()))
+            .map(|index| {
+                if index < keys.len() {
+                    keys.get(index).unwrap()
+                } else {
+                    legacy_keys.get(index - keys.len()).unwrap()
+                }
+            })
+            .map(|account_id| self.get_account(account_id))
             .collect()
     }
 }
@@ -2,12 +2,15 @@ use near_sdk::json_types::U128;
 use near_sdk::serde_json::{self, json};
 use near_sdk::AccountId;
 use near_sdk_sim::types::Balance;
+use near_sdk_sim::transaction::Transaction;
 use near_sdk_sim::{
-    call, deploy, init_simulator, to_yocto, ContractAccount, ExecutionResult, UserAccount,
+    call, deploy, init_simulator, to_yocto, view, ContractAccount, ExecutionResult, UserAccount,
     ViewResult,
 };
 
-use lockup_stake_metapool::{StakingContractContract, NEAR};
+use lockup_stake_metapool::{
+    BackendKind, HumanReadablePendingOperation, QueuedAdminAction, StakingContractContract, NEAR,
+};
 use near_sdk_sim::num_rational::Rational;
 
 pub const TGAS: u64 = 1_000_000_000_000;
@@ -22,9 +25,11 @@ pub const LOCKUP_ACCOUNT_ID: &str = "ab12345def.lockupy.testnet";
 
 near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
     LOCKUP_STAKE_METAPOOL_BYTES => "../res/lockup_stake_metapool.wasm",
+    LOCKUP_STAKE_METAPOOL_V1_BYTES => "../res/lockup_stake_metapool_v1.1.0.wasm",
     STNEAR_TOKEN_BYTES => "../res/metapool.wasm",
     WHITELIST_BYTES => "../res/whitelist.wasm",
     LOCKUP_BYTES => "../res/lockup_contract.wasm",
+    STAKING_POOL_BYTES => "../res/staking_pool.wasm",
 }
 
 pub fn meta_pool_contract_id() -> AccountId {
@@ -49,6 +54,22 @@ pub fn wait_epoch(user: &UserAccount) {
     //simulate_st_near_rewards(&user, 4);
 }
 
+/// waits for the delay of a queued admin action and executes it
+pub fn execute_admin_action(owner: &UserAccount, lockup_stake: &LockupStakeContract, id: u64) {
+    let queued: QueuedAdminAction = view!(lockup_stake.get_admin_action(id.into())).unwrap_json();
+    while owner.borrow_runtime().cur_block.epoch_height < queued.executable_epoch_height.0 {
+        wait_epoch(owner);
+    }
+    call(
+        owner,
+        lockup_stake.account_id(),
+        "execute_admin_action",
+        json!({ "id": id.to_string() }),
+        1,
+        0,
+    );
+}
+
 pub fn are_all_success(result: ExecutionResult) -> (bool, String) {
     let mut all_success = true;
     let mut all_results = String::new();
@@ -63,6 +84,20 @@ pub fn are_all_success(result: ExecutionResult) -> (bool, String) {
     (all_success, all_results)
 }
 
+/// returns the `EVENT_JSON:` logs of all the receipts, parsed
+pub fn events(result: &ExecutionResult) -> Vec<serde_json::Value> {
+    result
+        .promise_results()
+        .into_iter()
+        .flatten()
+        .flat_map(|r| r.logs().clone())
+        .filter_map(|log| {
+            log.strip_prefix("EVENT_JSON:")
+                .map(|json| serde_json::from_str(json).unwrap())
+        })
+        .collect()
+}
+
 pub fn assert_all_success(result: ExecutionResult) {
     let (all_success, all_results) = are_all_success(result);
     assert!(
@@ -145,6 +180,11 @@ pub fn storage_register(user: &UserAccount, account_id: AccountId) {
 }
 
 pub fn setup() -> (UserAccount,UserAccount, LockupStakeContract, UserAccount) {
+    setup_with_code(&LOCKUP_STAKE_METAPOOL_BYTES)
+}
+
+/// same as `setup`, deploying the given code for this contract
+pub fn setup_with_code(code: &[u8]) -> (UserAccount,UserAccount, LockupStakeContract, UserAccount) {
     let lockup_stake_initial_balance: Balance = 10 * NEAR;
     println!("start setup");
     let root = init_simulator(None);
@@ -197,10 +237,10 @@ pub fn setup() -> (UserAccount,UserAccount, LockupStakeContract, UserAccount) {
     let lockup_stake = deploy!(
         contract: StakingContractContract,
         contract_id: LOCKUP_STAKE_CONTRACT_ID.to_string(),
-        bytes: &LOCKUP_STAKE_METAPOOL_BYTES,
+        bytes: code,
         signer_account: meta_pool_contract_user,
         deposit: lockup_stake_initial_balance,
-        init_method: new(root.account_id(), meta_pool_contract_id())
+        init_method: new(root.account_id(), meta_pool_contract_id(), None, None)
     );
     assert_all_success(root.call(
         meta_pool_contract_id(),
@@ -221,6 +261,151 @@ pub fn setup() -> (UserAccount,UserAccount, LockupStakeContract, UserAccount) {
     (root, lockupy_testnet_account, lockup_stake, lockup)
 }
 
+/// deploys another instance of this contract, backed by `meta_pool_contract_id`
+pub fn deploy_lockup_stake(
+    root: &UserAccount,
+    contract_id: &str,
+    meta_pool_contract_id: AccountId,
+    backend_kind: Option<BackendKind>,
+) -> LockupStakeContract {
+    deploy!(
+        contract: StakingContractContract,
+        contract_id: contract_id.to_string(),
+        bytes: &LOCKUP_STAKE_METAPOOL_BYTES,
+        signer_account: root,
+        deposit: 10 * NEAR,
+        init_method: new(root.account_id(), meta_pool_contract_id, None, backend_kind)
+    )
+}
+
+/// deploys a core-contracts staking pool with a 10% reward fee
+pub fn deploy_staking_pool(root: &UserAccount, account_id: &str) -> UserAccount {
+    root.deploy_and_init(
+        &STAKING_POOL_BYTES,
+        AccountId::new_unchecked(account_id.to_string()),
+        "new",
+        &serde_json::to_vec(&json!({
+            "owner_id": root.account_id(),
+            "stake_public_key": "ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp",
+            "reward_fee_fraction": { "numerator": 10, "denominator": 100 },
+        }))
+        .unwrap(),
+        to_yocto("100"),
+        near_sdk_sim::DEFAULT_GAS,
+    )
+}
+
+/// deploys another Meta Pool contract and registers this contract in it
+pub fn deploy_meta_pool(root: &UserAccount, account_id: &str, lockup_stake: &LockupStakeContract) -> UserAccount {
+    let meta_pool = root.deploy_and_init(
+        &STNEAR_TOKEN_BYTES,
+        AccountId::new_unchecked(account_id.to_string()),
+        "new",
+        &serde_json::to_vec(&json!({
+            "owner_account_id": root.account_id(),
+            "treasury_account_id": "treasury",
+            "operator_account_id": "operator",
+            "meta_token_account_id": "meta-token",
+        }))
+        .unwrap(),
+        to_yocto("100"),
+        near_sdk_sim::DEFAULT_GAS,
+    );
+    assert_all_success(root.call(
+        meta_pool.account_id(),
+        "storage_deposit",
+        &serde_json::to_vec(&json!({ "account_id": lockup_stake.account_id() })).unwrap(),
+        20 * TGAS,
+        to_yocto("1"),
+    ));
+    meta_pool
+}
+
+/// redeploys this contract with the current code and migrates the state
+pub fn upgrade_contract(lockup_stake: &LockupStakeContract) {
+    let contract = &lockup_stake.user_account;
+    assert_all_success(
+        contract
+            .create_transaction(contract.account_id())
+            .deploy_contract(LOCKUP_STAKE_METAPOOL_BYTES.to_vec())
+            .function_call("migrate".into(), b"{}".to_vec(), near_sdk_sim::DEFAULT_GAS, 0)
+            .submit(),
+    );
+}
+
+/// `user` calls `method` on this contract, and blocks are produced only until the call runs
+/// and the account is busy: the call to Meta Pool and the callback are still in flight.
+/// Returns the account pending operation at that point
+pub fn call_until_busy(
+    user: &UserAccount,
+    lockup_stake: &LockupStakeContract,
+    method: &str,
+    args: serde_json::Value,
+    deposit: Balance,
+    gas: u64,
+) -> HumanReadablePendingOperation {
+    let nonce = user
+        .borrow_runtime()
+        .view_access_key(user.account_id.as_str(), &user.signer.public_key())
+        .unwrap()
+        .nonce
+        + 1;
+    let tx = Transaction::new(
+        user.account_id().to_string(),
+        user.signer.public_key(),
+        lockup_stake.account_id().to_string(),
+        nonce,
+        Default::default(),
+    )
+    .function_call(method.into(), serde_json::to_vec(&args).unwrap(), gas, deposit)
+    .sign(&user.signer);
+    user.borrow_runtime_mut().send_tx(tx);
+    loop {
+        user.borrow_runtime_mut().produce_block().unwrap();
+        if let Some(pending) = view!(lockup_stake.get_account_pending_operation(user.account_id()))
+            .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        {
+            return pending;
+        }
+    }
+}
+
+/// Produces blocks until the account is not busy, i.e. its callback ran
+pub fn wait_not_busy(user: &UserAccount, lockup_stake: &LockupStakeContract) {
+    while view!(lockup_stake.get_account_pending_operation(user.account_id()))
+        .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        .is_some()
+    {
+        user.borrow_runtime_mut().produce_block().unwrap();
+    }
+}
+
+/// Simulates a callback that never runs: `user` calls `method` on this contract and, while
+/// the call to Meta Pool is in flight, the contract code is swapped so the callback fails
+/// with MethodNotFound. The contract code is restored afterwards. The account stays busy.
+pub fn call_losing_callback(
+    user: &UserAccount,
+    lockup_stake: &LockupStakeContract,
+    method: &str,
+    args: serde_json::Value,
+    deposit: Balance,
+    gas: u64,
+) {
+    println!("call {} (losing callback) --accountId:{}", method, user.account_id);
+    call_until_busy(user, lockup_stake, method, args, deposit, gas);
+    let contract = &lockup_stake.user_account;
+    contract
+        .create_transaction(contract.account_id())
+        .deploy_contract(WHITELIST_BYTES.to_vec())
+        .submit()
+        .assert_success();
+    contract
+        .create_transaction(contract.account_id())
+        .deploy_contract(LOCKUP_STAKE_METAPOOL_BYTES.to_vec())
+        .submit()
+        .assert_success();
+}
+
 pub fn assert_between(value: Balance, from: &str, to: &str) {
     assert!(
         value >= to_yocto(from) && value <= to_yocto(to),
@@ -311,6 +496,13 @@ pub fn simulate_st_near_rewards(root: &UserAccount, nears: u32) {
         0,
     );
 }
+/// the sim framework doesn't provide block rewards: the NEAR sent to the pool
+/// is distributed as rewards by its next ping, in a new epoch
+pub fn simulate_staking_pool_rewards(root: &UserAccount, staking_pool: &UserAccount, nears: u32) {
+    root.transfer(staking_pool.account_id(), to_yocto(&nears.to_string()));
+    wait_epoch(&root);
+    call(&root, staking_pool.account_id(), "ping", json!({}), 0, 0);
+}
 pub fn st_near_set_busy(root: &UserAccount, value: bool) {
     println!("----");
     println!("set busy {}", value);
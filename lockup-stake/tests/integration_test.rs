@@ -7,7 +7,11 @@ use helpers::*;
 use near_sdk::serde_json::json;
 use near_sdk_sim::{call, to_yocto, view};
 
-use lockup_stake_metapool::NEAR;
+use lockup_stake_metapool::{
+    AdminAction, AllowlistEntry, BackendKind, HumanReadableAccount, HumanReadablePendingOperation,
+    HumanReadableUnstakeTranche, PendingOperationKind, PingResult,
+    PreviousBackend, QueuedAdminAction, Role, StNearPriceInfo, NEAR,
+};
 
 #[test]
 fn test_deposit_and_stake() {
@@ -567,3 +571,1326 @@ fn test_stake_with_nslp_clearing() {
         10000,
     );
 }
+
+/// Deploys the v1.1.0 code, stakes and unstakes, then upgrades to the current code
+/// and migrates the accounts. Balances must be the same before and after.
+/// res/lockup_stake_metapool_v1.1.0.wasm is the v1.1.0 source built with `build.sh`
+#[test]
+fn test_upgrade_from_v1() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) =
+        setup_with_code(&LOCKUP_STAKE_METAPOOL_V1_BYTES);
+
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    let user2 = create_user_and_stake("user2.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    let user3 = create_user_and_stake("user3.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    simulate_st_near_rewards(&root, 6);
+    assert_all_success(call!(root, lockup_stake.ping()));
+    call(
+        &user2,
+        lockup_stake.account_id(),
+        "unstake",
+        json!({ "amount": (1000 * NEAR).to_string() }),
+        0,
+        125 * TGAS,
+    );
+
+    let users = [&user1, &user2, &user3];
+    let accounts_before: Vec<HumanReadableAccount> = users
+        .iter()
+        .map(|user| view!(lockup_stake.get_account(user.account_id())).unwrap_json())
+        .collect();
+    let total_staked_before = to_int(view!(lockup_stake.get_total_staked_balance()));
+
+    upgrade_contract(&lockup_stake);
+    assert_eq!(view!(lockup_stake.get_state_version()).unwrap_json::<u32>(), 2);
+
+    // nothing moved yet, but all accounts are readable
+    assert_eq!(view!(lockup_stake.get_accounts_pending_migration()).unwrap_json::<u64>(), 3);
+    assert_eq!(view!(lockup_stake.get_number_of_accounts()).unwrap_json::<u64>(), 3);
+    // only the owner can migrate accounts
+    call_some_fail(&user1, lockup_stake.account_id(), "migrate_accounts", json!({ "limit": 2 }), 0);
+    call(&root, lockup_stake.account_id(), "migrate_accounts", json!({ "limit": 2 }), 0, 0);
+    assert_eq!(view!(lockup_stake.get_accounts_pending_migration()).unwrap_json::<u64>(), 1);
+    call(&root, lockup_stake.account_id(), "migrate_accounts", json!({ "limit": 2 }), 0, 0);
+    assert_eq!(view!(lockup_stake.get_accounts_pending_migration()).unwrap_json::<u64>(), 0);
+    assert_eq!(view!(lockup_stake.get_number_of_accounts()).unwrap_json::<u64>(), 3);
+
+    for (user, before) in users.iter().zip(accounts_before.iter()) {
+        let after: HumanReadableAccount =
+            view!(lockup_stake.get_account(user.account_id())).unwrap_json();
+        assert_eq!(after.staked_balance, before.staked_balance);
+        assert_eq!(after.unstaked_balance, before.unstaked_balance);
+        assert_eq!(after.can_withdraw, before.can_withdraw);
+    }
+    assert_eq!(
+        to_int(view!(lockup_stake.get_total_staked_balance())),
+        total_staked_before
+    );
+
+    // the migrated accounts keep working
+    call(
+        &user1,
+        lockup_stake.account_id(),
+        "unstake",
+        json!({ "amount": (1000 * NEAR).to_string() }),
+        0,
+        125 * TGAS,
+    );
+    assert_tolerance(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        1000 * NEAR,
+        10000,
+    );
+}
+
+/// Redeploys over the state written by this code, `migrate` keeps the current layout
+#[test]
+fn test_upgrade_current_layout() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    assert_eq!(view!(lockup_stake.get_state_version()).unwrap_json::<u32>(), 2);
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    let user2 = create_user_and_stake("user2.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+    call(&user2, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+
+    let users = [&user1, &user2];
+    let accounts_before: Vec<HumanReadableAccount> = users
+        .iter()
+        .map(|user| view!(lockup_stake.get_account(user.account_id())).unwrap_json())
+        .collect();
+    let total_staked_before = to_int(view!(lockup_stake.get_total_staked_balance()));
+
+    upgrade_contract(&lockup_stake);
+    assert_eq!(view!(lockup_stake.get_state_version()).unwrap_json::<u32>(), 2);
+    assert_eq!(view!(lockup_stake.get_accounts_pending_migration()).unwrap_json::<u64>(), 0);
+    for (user, before) in users.iter().zip(accounts_before.iter()) {
+        let after: HumanReadableAccount =
+            view!(lockup_stake.get_account(user.account_id())).unwrap_json();
+        assert_eq!(after.staked_balance, before.staked_balance);
+        assert_eq!(after.unstaked_balance, before.unstaked_balance);
+        assert_eq!(after.can_withdraw, before.can_withdraw);
+    }
+    assert_eq!(to_int(view!(lockup_stake.get_total_staked_balance())), total_staked_before);
+    // only the owner (or the contract itself) can migrate
+    call_some_fail(&user1, lockup_stake.account_id(), "migrate", json!({}), 0);
+}
+
+/// Stages new code as the owner and checks the hash verification before deploying
+#[test]
+fn test_stage_upgrade() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+
+    // only the owner can stage code
+    assert_some_fail(user1.call(
+        lockup_stake.account_id(),
+        "stage_upgrade",
+        &LOCKUP_STAKE_METAPOOL_BYTES,
+        near_sdk_sim::DEFAULT_GAS,
+        10 * NEAR,
+    ));
+    let staged = root.call(
+        lockup_stake.account_id(),
+        "stage_upgrade",
+        &LOCKUP_STAKE_METAPOOL_BYTES,
+        near_sdk_sim::DEFAULT_GAS,
+        10 * NEAR,
+    );
+    let hash: String = staged.unwrap_json();
+    assert_all_success(staged);
+    assert_eq!(
+        view!(lockup_stake.get_staged_upgrade_hash()).unwrap_json::<Option<String>>(),
+        Some(hash.clone())
+    );
+
+    // wrong hash is rejected and the code stays staged
+    call_some_fail(
+        &root,
+        lockup_stake.account_id(),
+        "deploy_staged_upgrade",
+        json!({ "expected_hash": "11111111111111111111111111111111" }),
+        1,
+    );
+    assert_eq!(
+        view!(lockup_stake.get_staged_upgrade_hash()).unwrap_json::<Option<String>>(),
+        Some(hash.clone())
+    );
+
+    // the storage cost is returned
+    let balance_before = root.account().unwrap().amount;
+    let result = root.call(
+        lockup_stake.account_id(),
+        "cancel_staged_upgrade",
+        &json!({}).to_string().into_bytes(),
+        near_sdk_sim::DEFAULT_GAS,
+        1,
+    );
+    assert_eq!(events(&result)[0]["event"], "upgrade_cancelled");
+    assert_eq!(events(&result)[0]["data"]["code_hash"], hash);
+    assert_all_success(result);
+    let storage_cost = LOCKUP_STAKE_METAPOOL_BYTES.len() as u128 * to_yocto("0.00001");
+    assert!(root.account().unwrap().amount - balance_before > storage_cost * 95 / 100);
+    assert_eq!(
+        view!(lockup_stake.get_staged_upgrade_hash()).unwrap_json::<Option<String>>(),
+        None
+    );
+}
+
+/// Stages the current code, deploys it with the right hash and checks `migrate` ran on the current layout
+#[test]
+fn test_deploy_staged_upgrade() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+    let account_before: HumanReadableAccount =
+        view!(lockup_stake.get_account(user1.account_id())).unwrap_json();
+
+    let staged = root.call(
+        lockup_stake.account_id(),
+        "stage_upgrade",
+        &LOCKUP_STAKE_METAPOOL_BYTES,
+        near_sdk_sim::DEFAULT_GAS,
+        10 * NEAR,
+    );
+    let hash: String = staged.unwrap_json();
+    assert_all_success(staged);
+
+    // not enough gas left for migrate: rejected, the code stays staged
+    assert_some_fail(root.call(
+        lockup_stake.account_id(),
+        "deploy_staged_upgrade",
+        &near_sdk::serde_json::to_vec(&json!({ "expected_hash": hash })).unwrap(),
+        50 * TGAS,
+        1,
+    ));
+    assert_eq!(
+        view!(lockup_stake.get_staged_upgrade_hash()).unwrap_json::<Option<String>>(),
+        Some(hash.clone())
+    );
+
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "deploy_staged_upgrade",
+        json!({ "expected_hash": hash }),
+        1,
+        200 * TGAS,
+    );
+    assert_eq!(
+        view!(lockup_stake.get_staged_upgrade_hash()).unwrap_json::<Option<String>>(),
+        None
+    );
+    assert_eq!(view!(lockup_stake.get_state_version()).unwrap_json::<u32>(), 2);
+    let account_after: HumanReadableAccount =
+        view!(lockup_stake.get_account(user1.account_id())).unwrap_json();
+    assert_eq!(account_after.staked_balance, account_before.staked_balance);
+    assert_eq!(account_after.unstaked_balance, account_before.unstaked_balance);
+
+    // the contract keeps working after the upgrade
+    assert_all_success(call!(root, lockup_stake.ping()));
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+}
+
+#[test]
+fn test_pause_staking() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    let guardian = root.create_user(near_sdk::AccountId::new_unchecked("guardian".into()), 10 * NEAR);
+
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "grant_role",
+        json!({ "role": "guardian", "account_id": guardian.account_id() }),
+        1,
+        0,
+    );
+    call(
+        &guardian,
+        lockup_stake.account_id(),
+        "pause_staking",
+        json!({ "pause_unstake": true }),
+        1,
+        0,
+    );
+    assert!(view!(lockup_stake.is_staking_paused()).unwrap_json::<bool>());
+    assert!(view!(lockup_stake.is_unstake_paused()).unwrap_json::<bool>());
+
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    call_some_fail(
+        &user1,
+        lockup_stake.account_id(),
+        "unstake",
+        json!({ "amount": (100 * NEAR).to_string() }),
+        0,
+    );
+    // the guardian can not resume
+    call_some_fail(&guardian, lockup_stake.account_id(), "resume_staking", json!({}), 1);
+
+    call(&root, lockup_stake.account_id(), "resume_staking", json!({}), 1, 0);
+    assert!(!view!(lockup_stake.is_staking_paused()).unwrap_json::<bool>());
+    assert_all_success(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_total_balance(user1.account_id()))),
+        to_yocto("10100")
+    );
+}
+
+/// The pending operation is visible while the call to Meta Pool is in flight, and cleared by its callback
+#[test]
+fn test_pending_operation_view() {
+    let (_root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert!(view!(lockup_stake.get_account_pending_operation(user1.account_id()))
+        .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        .is_none());
+
+    // DEPOSIT
+    let staked_before = to_int(view!(lockup_stake.get_account_staked_balance(user1.account_id())));
+    let pending = call_until_busy(&user1, &lockup_stake, "deposit_and_stake", json!({}), 1000 * NEAR, 75 * TGAS);
+    assert_eq!(pending.kind, PendingOperationKind::DepositAndStake);
+    assert_eq!(pending.amount.0, 1000 * NEAR);
+    assert_eq!(pending.shares.0, 0);
+    assert_eq!(pending.epoch_height.0, user1.borrow_runtime().current_block().epoch_height);
+    // nothing is credited until the callback runs
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_staked_balance(user1.account_id()))),
+        staked_before
+    );
+    wait_not_busy(&user1, &lockup_stake);
+    assert_tolerance(
+        to_int(view!(lockup_stake.get_account_staked_balance(user1.account_id()))),
+        staked_before + 1000 * NEAR,
+        10,
+    );
+
+    // UNSTAKE
+    let shares = to_int(view!(lockup_stake.get_account_shares(user1.account_id())));
+    let pending = call_until_busy(&user1, &lockup_stake, "unstake_all", json!({}), 0, 125 * TGAS);
+    assert_eq!(pending.kind, PendingOperationKind::Unstake);
+    assert_eq!(pending.amount.0, 0);
+    assert_eq!(pending.shares.0, shares);
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        0
+    );
+    wait_not_busy(&user1, &lockup_stake);
+    assert_eq!(to_int(view!(lockup_stake.get_account_shares(user1.account_id()))), 0);
+    assert!(to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))) > 0);
+}
+
+/// Forces accounts into the stuck state (callback lost) and releases them with `recover_account`
+#[test]
+fn test_recover_stuck_account() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    // a shorter lock could be recovered while its callback is still on the way
+    call_some_fail(
+        &root,
+        lockup_stake.account_id(),
+        "set_busy_lock_timeout_blocks",
+        json!({ "blocks": "20" }),
+        1,
+    );
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "set_busy_lock_timeout_blocks",
+        json!({ "blocks": "300" }),
+        1,
+        0,
+    );
+
+    // DEPOSIT, the callback never runs
+    call_losing_callback(&user1, &lockup_stake, "deposit_and_stake", json!({}), 1000 * NEAR, 75 * TGAS);
+    let pending: Option<HumanReadablePendingOperation> =
+        view!(lockup_stake.get_account_pending_operation(user1.account_id())).unwrap_json();
+    let pending = pending.unwrap();
+    assert_eq!(pending.kind, PendingOperationKind::DepositAndStake);
+    assert_eq!(pending.amount.0, 1000 * NEAR);
+    // the account is locked
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    // and the lock has not expired yet
+    call_some_fail(
+        &root,
+        lockup_stake.account_id(),
+        "recover_account",
+        json!({ "account_id": user1.account_id() }),
+        0,
+    );
+    root.borrow_runtime_mut().produce_blocks(300).unwrap();
+    assert_eq!(view!(lockup_stake.get_in_flight_operations()).unwrap_json::<String>(), "1");
+    // anybody can recover
+    call(
+        &lockupy_testnet,
+        lockup_stake.account_id(),
+        "recover_account",
+        json!({ "account_id": user1.account_id() }),
+        0,
+        0,
+    );
+    assert_eq!(view!(lockup_stake.get_in_flight_operations()).unwrap_json::<String>(), "0");
+    assert!(view!(lockup_stake.get_account_pending_operation(user1.account_id()))
+        .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        .is_none());
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_shares(user1.account_id()))),
+        11000 * NEAR
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_total_stake_shares())),
+        balance_shares_metapool(&lockup_stake.user_account)
+    );
+
+    // UNSTAKE, the callback never runs
+    call_losing_callback(
+        &user1,
+        &lockup_stake,
+        "unstake",
+        json!({ "amount": (1000 * NEAR).to_string() }),
+        0,
+        125 * TGAS,
+    );
+    root.borrow_runtime_mut().produce_blocks(300).unwrap();
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "recover_account",
+        json!({ "account_id": user1.account_id() }),
+        0,
+        0,
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_shares(user1.account_id()))),
+        10000 * NEAR
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        1000 * NEAR
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_total_stake_shares())),
+        balance_shares_metapool(&lockup_stake.user_account)
+    );
+
+    // two accounts stuck: the Meta Pool totals can't be attributed to either of them
+    let user2 = create_user_and_stake("user2.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    call_losing_callback(&user1, &lockup_stake, "deposit_and_stake", json!({}), 1000 * NEAR, 75 * TGAS);
+    call_losing_callback(&user2, &lockup_stake, "deposit_and_stake", json!({}), 500 * NEAR, 75 * TGAS);
+    root.borrow_runtime_mut().produce_blocks(300).unwrap();
+    assert_eq!(view!(lockup_stake.get_in_flight_operations()).unwrap_json::<String>(), "2");
+    call_some_fail(
+        &root,
+        lockup_stake.account_id(),
+        "recover_account",
+        json!({ "account_id": user1.account_id() }),
+        0,
+    );
+    assert!(view!(lockup_stake.get_account_pending_operation(user1.account_id()))
+        .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        .is_some());
+    // once the other one is released by hand, the account can be reconciled
+    call(&root, lockup_stake.account_id(), "set_not_busy", json!({ "account_id": user2.account_id() }), 1, 0);
+    assert_eq!(view!(lockup_stake.get_in_flight_operations()).unwrap_json::<String>(), "1");
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "recover_account",
+        json!({ "account_id": user1.account_id() }),
+        0,
+        0,
+    );
+    assert_eq!(view!(lockup_stake.get_in_flight_operations()).unwrap_json::<String>(), "0");
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_shares(user1.account_id()))),
+        11000 * NEAR
+    );
+}
+
+#[test]
+fn test_events() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+
+    let result = call!(user1, lockup_stake.deposit_and_stake(), to_yocto("100"), 75 * TGAS);
+    assert_eq!(
+        events(&result)
+            .into_iter()
+            .filter(|event| event["event"] != "st_near_price_updated")
+            .collect::<Vec<_>>(),
+        vec![json!({
+            "standard": "lockup_stake_metapool",
+            "version": "1.0.0",
+            "event": "deposit_and_stake",
+            "data": {
+                "account_id": user1.account_id(),
+                "amount": to_yocto("100").to_string(),
+                "shares": to_yocto("100").to_string(),
+            }
+        })]
+    );
+    assert_all_success(result);
+
+    let result = root.call(
+        lockup_stake.account_id(),
+        "set_owner_id",
+        &near_sdk::serde_json::to_vec(&json!({ "new_owner_id": "new-owner" })).unwrap(),
+        near_sdk_sim::DEFAULT_GAS,
+        1,
+    );
+    assert_eq!(events(&result)[0]["event"], "admin_action_queued");
+    assert_eq!(events(&result)[0]["data"]["action"]["kind"], "propose_owner");
+    assert_eq!(events(&result)[0]["data"]["action"]["owner_id"], "new-owner");
+}
+
+#[test]
+fn test_two_step_ownership() {
+    let (root, _lockupy_testnet, lockup_stake, _lockup) = setup();
+    let new_owner = root.create_user("new-owner.root".parse().unwrap(), to_yocto("100"));
+    let typo = root.create_user("new-ownr.root".parse().unwrap(), to_yocto("100"));
+
+    // a typo can be cancelled
+    call(&root, lockup_stake.account_id(), "propose_owner", json!({ "new_owner_id": typo.account_id() }), 1, 0);
+    // the proposal waits for the admin action delay
+    call_some_fail(&root, lockup_stake.account_id(), "execute_admin_action", json!({ "id": "0" }), 1);
+    execute_admin_action(&root, &lockup_stake, 0);
+    assert_eq!(
+        view!(lockup_stake.get_pending_owner_id()).unwrap_json::<Option<near_sdk::AccountId>>(),
+        Some(typo.account_id())
+    );
+    call(&root, lockup_stake.account_id(), "cancel_owner_proposal", json!({}), 1, 0);
+    call_some_fail(&typo, lockup_stake.account_id(), "accept_ownership", json!({}), 1);
+    assert_eq!(view!(lockup_stake.get_owner_id()).unwrap_json::<near_sdk::AccountId>(), root.account_id());
+
+    call(&root, lockup_stake.account_id(), "propose_owner", json!({ "new_owner_id": new_owner.account_id() }), 1, 0);
+    execute_admin_action(&root, &lockup_stake, 1);
+    // only the proposed owner can accept
+    call_some_fail(&typo, lockup_stake.account_id(), "accept_ownership", json!({}), 1);
+    assert_eq!(view!(lockup_stake.get_owner_id()).unwrap_json::<near_sdk::AccountId>(), root.account_id());
+    let result = new_owner.call(
+        lockup_stake.account_id(),
+        "accept_ownership",
+        b"{}",
+        near_sdk_sim::DEFAULT_GAS,
+        1,
+    );
+    assert_eq!(events(&result)[0]["event"], "owner_changed");
+    assert_all_success(result);
+    assert_eq!(view!(lockup_stake.get_owner_id()).unwrap_json::<near_sdk::AccountId>(), new_owner.account_id());
+    assert_eq!(view!(lockup_stake.get_pending_owner_id()).unwrap_json::<Option<near_sdk::AccountId>>(), None);
+    // the old owner lost its rights
+    call_some_fail(&root, lockup_stake.account_id(), "set_busy_lock_timeout_blocks", json!({ "blocks": "600" }), 1);
+}
+
+#[test]
+fn test_roles() {
+    let (root, _lockupy_testnet, lockup_stake, _lockup) = setup();
+    let operator = root.create_user("operator.root".parse().unwrap(), to_yocto("100"));
+
+    // only the owner grants roles
+    call_some_fail(
+        &operator,
+        lockup_stake.account_id(),
+        "grant_role",
+        json!({ "role": "operator", "account_id": operator.account_id() }),
+        1,
+    );
+    call_some_fail(&operator, lockup_stake.account_id(), "migrate_accounts", json!({ "limit": 10 }), 0);
+    // anybody can ping
+    assert_all_success(call!(operator, lockup_stake.ping()));
+
+    let result = root.call(
+        lockup_stake.account_id(),
+        "grant_role",
+        &json!({ "role": "operator", "account_id": operator.account_id() }).to_string().into_bytes(),
+        near_sdk_sim::DEFAULT_GAS,
+        1,
+    );
+    assert_eq!(events(&result)[0]["event"], "role_granted");
+    assert_eq!(events(&result)[0]["data"]["role"], "operator");
+    assert_all_success(result);
+    assert!(view!(lockup_stake.has_role(Role::Operator, operator.account_id())).unwrap_json::<bool>());
+    assert!(!view!(lockup_stake.has_role(Role::Guardian, operator.account_id())).unwrap_json::<bool>());
+    assert_eq!(
+        view!(lockup_stake.get_role_members(Role::Operator)).unwrap_json::<Vec<near_sdk::AccountId>>(),
+        vec![operator.account_id()]
+    );
+
+    // an operator migrates accounts, but can't pause or change the config
+    call(&operator, lockup_stake.account_id(), "migrate_accounts", json!({ "limit": 10 }), 0, 0);
+    call_some_fail(&operator, lockup_stake.account_id(), "pause_staking", json!({}), 1);
+    call_some_fail(&operator, lockup_stake.account_id(), "set_busy_lock_timeout_blocks", json!({ "blocks": "600" }), 1);
+
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "revoke_role",
+        json!({ "role": "operator", "account_id": operator.account_id() }),
+        1,
+        0,
+    );
+    assert!(!view!(lockup_stake.has_role(Role::Operator, operator.account_id())).unwrap_json::<bool>());
+    call_some_fail(&operator, lockup_stake.account_id(), "migrate_accounts", json!({ "limit": 10 }), 0);
+}
+
+#[test]
+fn test_admin_actions() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+
+    let result = root.call(
+        lockup_stake.account_id(),
+        "queue_admin_action",
+        &json!({ "action": { "kind": "set_meta_pool_contract_id", "meta_pool_contract_id": "meta-pool-v2.near" } })
+            .to_string()
+            .into_bytes(),
+        near_sdk_sim::DEFAULT_GAS,
+        1,
+    );
+    assert_eq!(events(&result)[0]["event"], "admin_action_queued");
+    assert_all_success(result);
+    let actions: Vec<QueuedAdminAction> = view!(lockup_stake.get_admin_actions()).unwrap_json();
+    assert_eq!(actions.len(), 1);
+    assert_eq!(
+        actions[0].action,
+        AdminAction::SetMetaPoolContractId {
+            meta_pool_contract_id: "meta-pool-v2.near".parse().unwrap()
+        }
+    );
+    assert_eq!(actions[0].executable_epoch_height.0, actions[0].queued_epoch_height.0 + 4);
+
+    // only the owner, and not before the delay
+    call_some_fail(&user1, lockup_stake.account_id(), "cancel_admin_action", json!({ "id": "0" }), 1);
+    call_some_fail(&root, lockup_stake.account_id(), "execute_admin_action", json!({ "id": "0" }), 1);
+    for _ in 0..4 {
+        wait_epoch(&root);
+    }
+    call_some_fail(&user1, lockup_stake.account_id(), "execute_admin_action", json!({ "id": "0" }), 1);
+    assert_eq!(view!(lockup_stake.get_admin_actions()).unwrap_json::<Vec<QueuedAdminAction>>().len(), 1);
+
+    // an operator can execute, but not queue nor cancel
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "grant_role",
+        json!({ "role": "operator", "account_id": lockupy_testnet.account_id() }),
+        1,
+        0,
+    );
+    call_some_fail(
+        &lockupy_testnet,
+        lockup_stake.account_id(),
+        "queue_admin_action",
+        json!({ "action": { "kind": "set_admin_action_delay", "epochs": "1" } }),
+        1,
+    );
+    call_some_fail(&lockupy_testnet, lockup_stake.account_id(), "cancel_admin_action", json!({ "id": "0" }), 1);
+
+    // the delay is changed with a queued action too, it can't be removed
+    call_some_fail(
+        &root,
+        lockup_stake.account_id(),
+        "queue_admin_action",
+        json!({ "action": { "kind": "set_admin_action_delay", "epochs": "0" } }),
+        1,
+    );
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "queue_admin_action",
+        json!({ "action": { "kind": "set_admin_action_delay", "epochs": "1" } }),
+        1,
+        0,
+    );
+    call_some_fail(&lockupy_testnet, lockup_stake.account_id(), "execute_admin_action", json!({ "id": "1" }), 1);
+    execute_admin_action(&lockupy_testnet, &lockup_stake, 1);
+    let config: near_sdk::serde_json::Value = view!(lockup_stake.get_config()).unwrap_json();
+    assert_eq!(config["admin_action_delay_epochs"], "1");
+
+    call(&root, lockup_stake.account_id(), "cancel_admin_action", json!({ "id": "0" }), 1, 0);
+    assert!(view!(lockup_stake.get_admin_actions()).unwrap_json::<Vec<QueuedAdminAction>>().is_empty());
+    assert_eq!(
+        view!(lockup_stake.get_admin_action(0.into())).unwrap_json::<Option<QueuedAdminAction>>(),
+        None
+    );
+}
+
+#[test]
+fn test_switch_meta_pool() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    let meta_pool_v2 = deploy_meta_pool(&root, "meta-pool-v2.root", &lockup_stake);
+
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "queue_admin_action",
+        json!({ "action": { "kind": "set_meta_pool_contract_id", "meta_pool_contract_id": meta_pool_v2.account_id() } }),
+        1,
+        0,
+    );
+    execute_admin_action(&root, &lockup_stake, 0);
+    // deposits are paused until the owner checks the new contract
+    assert!(view!(lockup_stake.is_staking_paused()).unwrap_json::<bool>());
+    let previous: Option<PreviousBackend> = view!(lockup_stake.get_previous_backend()).unwrap_json();
+    let previous = previous.unwrap();
+    assert_eq!(previous.meta_pool_contract_id, meta_pool_contract_id());
+    assert_eq!(previous.total_stake_shares.0, to_yocto("10000"));
+    assert_eq!(to_int(view!(lockup_stake.get_total_stake_shares())), 0);
+    assert_eq!(
+        view!(lockup_stake.get_account_meta_pool_contract_id(user1.account_id())).unwrap_json::<near_sdk::AccountId>(),
+        meta_pool_contract_id()
+    );
+    // balances at the previous contract are still reported
+    assert_between(to_int(view!(lockup_stake.get_total_staked_balance())), "9999.99", "10000.01");
+
+    assert_all_success(call!(root, lockup_stake.ping()));
+    call(&root, lockup_stake.account_id(), "resume_staking", json!({}), 1, 0);
+    // new deposits go to the new contract
+    let user2 = create_user_and_stake("user2.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_eq!(
+        view!(lockup_stake.get_account_meta_pool_contract_id(user2.account_id())).unwrap_json::<near_sdk::AccountId>(),
+        meta_pool_v2.account_id()
+    );
+    assert_eq!(to_int(view!(lockup_stake.get_total_stake_shares())), to_yocto("10000"));
+    // accounts at the previous contract can't deposit
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+
+    // but they can unstake and withdraw there
+    assert_all_success(call!(user1, lockup_stake.unstake_all(), 0, 125 * TGAS));
+    for _ in 0..4 {
+        wait_epoch(&root);
+    }
+    call(&root, meta_pool_contract_id(), "test_simulate_retrieval", json!({}), to_yocto("10000"), 0);
+    assert_all_success(call!(user1, lockup_stake.withdraw_all(), 0, 125 * TGAS));
+    let previous: Option<PreviousBackend> = view!(lockup_stake.get_previous_backend()).unwrap_json();
+    assert!(previous.unwrap().is_empty());
+    assert_eq!(to_int(view!(lockup_stake.get_account_total_balance(user1.account_id()))), 0);
+
+    // and start over at the new contract
+    assert_all_success(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    assert_eq!(
+        view!(lockup_stake.get_account_meta_pool_contract_id(user1.account_id())).unwrap_json::<near_sdk::AccountId>(),
+        meta_pool_v2.account_id()
+    );
+}
+
+#[test]
+fn test_staking_pool_backend() {
+    let (root, lockupy_testnet, _lockup_stake, _lockup) = setup();
+    let staking_pool = deploy_staking_pool(&root, "staking-pool.root");
+    let lockup_stake = deploy_lockup_stake(
+        &root,
+        "pool-lockup-stake.root",
+        staking_pool.account_id(),
+        Some(BackendKind::StakingPool),
+    );
+    assert_eq!(view!(lockup_stake.get_backend_kind()).unwrap_json::<BackendKind>(), BackendKind::StakingPool);
+
+    let staked_at_pool = || -> u128 {
+        root.view(
+            staking_pool.account_id(),
+            "get_account_staked_balance",
+            &json!({ "account_id": lockup_stake.account_id() }).to_string().into_bytes(),
+        )
+        .unwrap_json::<near_sdk::json_types::U128>()
+        .0
+    };
+
+    // the stake queries the pool before and after it, it needs more gas than with Meta Pool
+    let user1 = lockupy_testnet.create_user("user1.lockupy.testnet".parse().unwrap(), to_yocto("100000"));
+    storage_register(&lockupy_testnet, user1.account_id());
+    let user2 = lockupy_testnet.create_user("user2.lockupy.testnet".parse().unwrap(), to_yocto("100000"));
+    storage_register(&lockupy_testnet, user2.account_id());
+    assert_all_success(call!(user1, lockup_stake.deposit_and_stake(), to_yocto("10000"), 150 * TGAS));
+    // the first shares are minted 1:1 with the NEAR staked at the pool
+    assert_between(staked_at_pool(), "9999.99", "10000.01");
+    assert_eq!(to_int(view!(lockup_stake.get_account_shares(user1.account_id()))), staked_at_pool());
+
+    // operations are serialized: the staked balance change is the stake's only
+    let pending = call_until_busy(&user2, &lockup_stake, "deposit_and_stake", json!({}), to_yocto("5000"), 150 * TGAS);
+    assert_eq!(pending.kind, PendingOperationKind::DepositAndStake);
+    assert_some_fail(call!(user1, lockup_stake.unstake(to_yocto("1000").into()), 0, 200 * TGAS));
+    assert_some_fail(call!(root, lockup_stake.ping()));
+    wait_not_busy(&user2, &lockup_stake);
+    // shares for the change in the staked balance, at the pool's price
+    let total_shares = to_int(view!(lockup_stake.get_total_stake_shares()));
+    let user2_shares = to_int(view!(lockup_stake.get_account_shares(user2.account_id())));
+    assert_eq!(
+        total_shares,
+        to_int(view!(lockup_stake.get_account_shares(user1.account_id()))) + user2_shares
+    );
+    assert_between(user2_shares, "4999.99", "5000.01");
+
+    // price and fee come from the pool
+    let ping: PingResult = call!(root, lockup_stake.ping()).unwrap_json();
+    assert!(ping.updated);
+    assert_eq!(ping.reward_fee_bp, 1000);
+    assert_eq!(ping.st_near_balance.0, 0);
+
+    assert_all_success(call!(
+        user1,
+        lockup_stake.unstake(to_yocto("1000").into()),
+        0,
+        200 * TGAS
+    ));
+    assert_between(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        "999.99",
+        "1000.01",
+    );
+    assert!(!view!(lockup_stake.is_account_unstaked_balance_available(user1.account_id())).unwrap_json::<bool>());
+    for _ in 0..5 {
+        wait_epoch(&root);
+    }
+    let balance_before = user1.account().unwrap().amount;
+    assert_all_success(call!(user1, lockup_stake.withdraw_all(), 0, 125 * TGAS));
+    assert_between(user1.account().unwrap().amount - balance_before, "999.9", "1000.01");
+    assert_eq!(to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))), 0);
+
+    // rewards received after the last ping are paid to the accounts unstaking
+    assert_all_success(call!(root, lockup_stake.ping()));
+    let cached_price = view!(lockup_stake.get_st_near_price_info()).unwrap_json::<StNearPriceInfo>().st_near_price.0;
+    simulate_staking_pool_rewards(&root, &staking_pool, 1000);
+    assert!(staked_at_pool() > to_int(view!(lockup_stake.get_total_staked_balance())));
+    let user2_shares = to_int(view!(lockup_stake.get_account_shares(user2.account_id())));
+    assert_all_success(call!(user2, lockup_stake.unstake_all(), 0, 200 * TGAS));
+    let user2_unstaked = to_int(view!(lockup_stake.get_account_unstaked_balance(user2.account_id())));
+    assert!(user2_unstaked > user2_shares / NEAR * cached_price);
+    // the last shares unstake what's left, nothing stays staked at the pool
+    assert_all_success(call!(user1, lockup_stake.unstake_all(), 0, 200 * TGAS));
+    assert_eq!(to_int(view!(lockup_stake.get_total_stake_shares())), 0);
+    assert!(staked_at_pool() < 10);
+
+    // no Meta Pool to reconcile against
+    call_some_fail(&root, lockup_stake.account_id(), "recover_account", json!({ "account_id": user1.account_id() }), 0);
+}
+
+#[test]
+fn test_ping_status() {
+    let (root, _lockupy_testnet, lockup_stake, _lockup) = setup();
+
+    let result = call!(root, lockup_stake.ping());
+    let ping: PingResult = result.unwrap_json();
+    assert_all_success(result);
+    assert!(ping.updated);
+    assert_eq!(ping.st_near_balance.0, balance_shares_metapool(&lockup_stake.user_account));
+    let status: near_sdk::serde_json::Value = view!(lockup_stake.get_ping_status()).unwrap_json();
+    assert_ne!(status["last_success_block_height"], "0");
+    assert_eq!(status["consecutive_failures"], 0);
+    assert_eq!(status["last_balance_failure_block_height"], "0");
+
+    // backed by a contract without the Meta Pool views, every query fails
+    let broken = deploy_lockup_stake(&root, "broken-lockup-stake", WHITELIST_ACCOUNT_ID.parse().unwrap(), None);
+    for _ in 0..2 {
+        let result = call!(root, broken.ping());
+        let failures: Vec<_> = events(&result)
+            .into_iter()
+            .filter(|event| event["event"] == "ping_failed")
+            .collect();
+        assert_eq!(failures.len(), 1);
+        assert!(!result.unwrap_json::<PingResult>().updated);
+    }
+    let status: near_sdk::serde_json::Value = view!(broken.get_ping_status()).unwrap_json();
+    assert_eq!(status["last_success_block_height"], "0");
+    assert_ne!(status["last_failure_block_height"], "0");
+    assert_eq!(status["consecutive_failures"], 2);
+    // cached values are kept
+    let fee: near_sdk::serde_json::Value = view!(broken.get_reward_fee_fraction()).unwrap_json();
+    assert_eq!(fee["numerator"], 400);
+}
+
+#[test]
+fn test_price_staleness() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+
+    let info: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert_eq!(info.epoch_height.0, 0);
+    assert_all_success(call!(root, lockup_stake.ping()));
+    let info: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert!(info.epoch_height.0 > 0);
+    assert_eq!(info.age_epochs.0, 0);
+
+    call(&root, lockup_stake.account_id(), "set_max_price_age_epochs", json!({ "epochs": "1" }), 1, 0);
+    wait_epoch(&root);
+    wait_epoch(&root);
+    let info: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert_eq!(info.age_epochs.0, 2);
+    // the account view tells how old the price its staked balance uses is
+    let account: HumanReadableAccount = view!(lockup_stake.get_account(user1.account_id())).unwrap_json();
+    assert_eq!(account.st_near_price_epoch_height, info.epoch_height);
+    assert_eq!(account.st_near_price_age_epochs.0, 2);
+
+    // without gas to refresh it, the price is too old to convert NEAR to shares
+    assert_some_fail(call!(user1, lockup_stake.unstake((10 * NEAR).into()), 0, 30 * TGAS));
+    // with enough gas the price is queried first
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (10 * NEAR).to_string() }), 0, 125 * TGAS);
+    let info: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert_eq!(info.age_epochs.0, 0);
+}
+
+#[test]
+fn test_unstake_refreshes_price() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+
+    simulate_st_near_rewards(&root, 10);
+    // no ping, the cached price is still 1
+    let info: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert_eq!(info.st_near_price.0, NEAR);
+
+    let shares_before = to_int(view!(lockup_stake.get_account_shares(user1.account_id())));
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+
+    let info: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert!(info.st_near_price.0 > NEAR);
+    // converted with the current price, less than 1000 shares were unstaked
+    let shares_after = to_int(view!(lockup_stake.get_account_shares(user1.account_id())));
+    assert!(shares_before - shares_after < 1000 * NEAR);
+    assert_tolerance(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        1000 * NEAR,
+        1000,
+    );
+}
+
+#[test]
+fn test_unstake_tranches() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+    wait_epoch(&root);
+    assert_all_success(call!(root, lockup_stake.ping()));
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (500 * NEAR).to_string() }), 0, 125 * TGAS);
+
+    // each unstake keeps its own maturity epoch
+    let tranches: Vec<HumanReadableUnstakeTranche> =
+        view!(lockup_stake.get_account_unstake_tranches(user1.account_id())).unwrap_json();
+    assert_eq!(tranches.len(), 2);
+    assert_eq!(tranches[0].amount.0, 1000 * NEAR);
+    assert_eq!(tranches[1].amount.0, 500 * NEAR);
+    assert!(tranches[0].available_epoch_height.0 < tranches[1].available_epoch_height.0);
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        1500 * NEAR
+    );
+
+    // the lockup contract still sees the whole unstaked balance available at once
+    while root.borrow_runtime().cur_block.epoch_height < tranches[0].available_epoch_height.0 {
+        wait_epoch(&root);
+    }
+    let available: bool = view!(lockup_stake.is_account_unstaked_balance_available(user1.account_id())).unwrap_json();
+    assert!(!available);
+    while root.borrow_runtime().cur_block.epoch_height < tranches[1].available_epoch_height.0 {
+        wait_epoch(&root);
+    }
+    let available: bool = view!(lockup_stake.is_account_unstaked_balance_available(user1.account_id())).unwrap_json();
+    assert!(available);
+
+    // the earliest tranche is withdrawn first
+    call(&user1, lockup_stake.account_id(), "withdraw", json!({ "amount": (1200 * NEAR).to_string() }), 0, 100 * TGAS);
+    let tranches: Vec<HumanReadableUnstakeTranche> =
+        view!(lockup_stake.get_account_unstake_tranches(user1.account_id())).unwrap_json();
+    assert_eq!(tranches.len(), 1);
+    assert_eq!(tranches[0].amount.0, 300 * NEAR);
+    assert!(tranches[0].can_withdraw);
+}
+
+#[test]
+fn test_withdraw_matured_only() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+    let tranches: Vec<HumanReadableUnstakeTranche> =
+        view!(lockup_stake.get_account_unstake_tranches(user1.account_id())).unwrap_json();
+    while root.borrow_runtime().cur_block.epoch_height < tranches[0].available_epoch_height.0 {
+        wait_epoch(&root);
+    }
+    assert_all_success(call!(root, lockup_stake.ping()));
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (500 * NEAR).to_string() }), 0, 125 * TGAS);
+
+    // the first unstake is not blocked by the second one
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_withdrawable_balance(user1.account_id()))),
+        1000 * NEAR
+    );
+    let available: bool = view!(lockup_stake.is_account_unstaked_balance_available(user1.account_id())).unwrap_json();
+    assert!(!available);
+    assert_some_fail(call!(user1, lockup_stake.withdraw((1001 * NEAR).into()), 0, 100 * TGAS));
+
+    let balance_before = user1.account().unwrap().amount;
+    assert_all_success(call!(user1, lockup_stake.withdraw_all(), 0, 125 * TGAS));
+    assert_between(user1.account().unwrap().amount - balance_before, "999", "1000");
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        500 * NEAR
+    );
+    assert_eq!(to_int(view!(lockup_stake.get_account_withdrawable_balance(user1.account_id()))), 0);
+    // nothing matured left
+    assert_some_fail(call!(user1, lockup_stake.withdraw_all(), 0, 125 * TGAS));
+}
+
+#[test]
+fn test_restake_unstaked() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+
+    call(&user1, lockup_stake.account_id(), "unstake", json!({ "amount": (1000 * NEAR).to_string() }), 0, 125 * TGAS);
+    // Meta Pool releases the NEAR to stake it again, it must be matured
+    let (success, results) = are_all_success(call!(user1, lockup_stake.stake((500 * NEAR).into()), 0, 125 * TGAS));
+    assert!(!success);
+    assert!(results.contains("Meta Pool can only stake again the unstaked balance whose wait period is over"));
+    assert_some_fail(call!(user1, lockup_stake.stake_all(), 0, 125 * TGAS));
+
+    let tranches: Vec<HumanReadableUnstakeTranche> =
+        view!(lockup_stake.get_account_unstake_tranches(user1.account_id())).unwrap_json();
+    while root.borrow_runtime().cur_block.epoch_height < tranches[0].available_epoch_height.0 {
+        wait_epoch(&root);
+    }
+    assert_some_fail(call!(user1, lockup_stake.stake((1001 * NEAR).into()), 0, 125 * TGAS));
+    assert_all_success(call!(user1, lockup_stake.stake((400 * NEAR).into()), 0, 125 * TGAS));
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        600 * NEAR
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_staked_balance(user1.account_id()))),
+        9400 * NEAR
+    );
+
+    assert_all_success(call!(user1, lockup_stake.stake_all(), 0, 125 * TGAS));
+    assert_eq!(to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))), 0);
+    let tranches: Vec<HumanReadableUnstakeTranche> =
+        view!(lockup_stake.get_account_unstake_tranches(user1.account_id())).unwrap_json();
+    assert!(tranches.is_empty());
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_staked_balance(user1.account_id()))),
+        10000 * NEAR
+    );
+    let pending: Option<HumanReadablePendingOperation> =
+        view!(lockup_stake.get_account_pending_operation(user1.account_id())).unwrap_json();
+    assert!(pending.is_none());
+}
+
+#[test]
+fn test_deposit_then_stake() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+
+    // below the minimum deposit
+    assert_some_fail(call!(user1, lockup_stake.deposit(), to_yocto("5"), 50 * TGAS));
+    // held here until staked or withdrawn
+    assert_all_success(call!(user1, lockup_stake.deposit(), to_yocto("1000"), 50 * TGAS));
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_deposited_balance(user1.account_id()))),
+        1000 * NEAR
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))),
+        1000 * NEAR
+    );
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_withdrawable_balance(user1.account_id()))),
+        1000 * NEAR
+    );
+
+    // no unstaking delay for the deposited balance
+    let balance_before = user1.account().unwrap().amount;
+    assert_all_success(call!(user1, lockup_stake.withdraw((200 * NEAR).into()), 0, 50 * TGAS));
+    assert_between(user1.account().unwrap().amount - balance_before, "199", "200");
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_deposited_balance(user1.account_id()))),
+        800 * NEAR
+    );
+    // more than the deposited balance: all or nothing, the deposited part is kept if the rest can't be withdrawn
+    let balance_before = user1.account().unwrap().amount;
+    assert_some_fail(call!(user1, lockup_stake.withdraw((900 * NEAR).into()), 0, 125 * TGAS));
+    assert!(user1.account().unwrap().amount <= balance_before);
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_deposited_balance(user1.account_id()))),
+        800 * NEAR
+    );
+
+    assert_some_fail(call!(user1, lockup_stake.stake((801 * NEAR).into()), 0, 125 * TGAS));
+    assert_all_success(call!(user1, lockup_stake.stake((300 * NEAR).into()), 0, 125 * TGAS));
+    // the minimum was checked on deposit, a smaller part of it can be staked
+    assert_all_success(call!(user1, lockup_stake.stake((5 * NEAR).into()), 0, 125 * TGAS));
+    assert_all_success(call!(user1, lockup_stake.stake_all(), 0, 125 * TGAS));
+    assert_eq!(to_int(view!(lockup_stake.get_account_deposited_balance(user1.account_id()))), 0);
+    assert_eq!(to_int(view!(lockup_stake.get_account_unstaked_balance(user1.account_id()))), 0);
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_staked_balance(user1.account_id()))),
+        10800 * NEAR
+    );
+    let pending: Option<HumanReadablePendingOperation> =
+        view!(lockup_stake.get_account_pending_operation(user1.account_id())).unwrap_json();
+    assert!(pending.is_none());
+}
+
+#[test]
+fn test_price_guards() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+    assert_all_success(call!(root, lockup_stake.ping()));
+    let price_before: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "set_price_guards",
+        json!({
+            "max_price_change_bp": 1,
+            "allow_price_decrease": false,
+            "max_reward_fee_bp": 0,
+            "pause_on_rejected_update": true,
+        }),
+        1,
+        0,
+    );
+    // more than 0.01% price increase
+    simulate_st_near_rewards(&root, 5000);
+
+    let result = call!(root, lockup_stake.ping());
+    let rejected: Vec<_> = events(&result)
+        .into_iter()
+        .map(|event| event["event"].as_str().unwrap().to_string())
+        .filter(|event| event.ends_with("_rejected"))
+        .collect();
+    assert_all_success(result);
+    assert_eq!(rejected.len(), 2);
+
+    // cached values are kept and deposits paused
+    let price_after: StNearPriceInfo = view!(lockup_stake.get_st_near_price_info()).unwrap_json();
+    assert_eq!(price_after.st_near_price, price_before.st_near_price);
+    assert!(view!(lockup_stake.is_staking_paused()).unwrap_json::<bool>());
+    assert!(!view!(lockup_stake.is_unstake_paused()).unwrap_json::<bool>());
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+}
+
+#[test]
+fn test_deposit_limits() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+
+    // only the owner
+    call_some_fail(
+        &user1,
+        lockup_stake.account_id(),
+        "set_deposit_limits",
+        json!({ "min_deposit_amount": (100 * NEAR).to_string() }),
+        1,
+    );
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "set_deposit_limits",
+        json!({
+            "min_deposit_amount": (100 * NEAR).to_string(),
+            "max_account_stake": (15000 * NEAR).to_string(),
+            "max_total_stake": (20000 * NEAR).to_string(),
+        }),
+        1,
+        0,
+    );
+    let config: near_sdk::serde_json::Value = view!(lockup_stake.get_config()).unwrap_json();
+    assert_eq!(config["min_deposit_amount"], (100 * NEAR).to_string());
+
+    // below the minimum
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 50 * NEAR, 75 * TGAS));
+    // above the max per account
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 6000 * NEAR, 75 * TGAS));
+    assert_all_success(call!(user1, lockup_stake.deposit_and_stake(), 5000 * NEAR, 75 * TGAS));
+
+    // above the max total
+    let user2 = lockupy_testnet.create_user("user2.lockupy.testnet".parse().unwrap(), to_yocto("100000"));
+    storage_register(&lockupy_testnet, user2.account_id());
+    assert_some_fail(call!(user2, lockup_stake.deposit_and_stake(), 6000 * NEAR, 75 * TGAS));
+    assert_all_success(call!(user2, lockup_stake.deposit_and_stake(), 5000 * NEAR, 75 * TGAS));
+}
+
+#[test]
+fn test_gas_config() {
+    let (root, lockupy_testnet, lockup_stake, _lockup) = setup();
+    let user1 = create_user_and_stake("user1.lockupy.testnet".into(), &lockupy_testnet, &lockup_stake);
+
+    let mut gas_config: near_sdk::serde_json::Value = view!(lockup_stake.get_gas_config()).unwrap_json();
+    assert_eq!(gas_config["deposit_and_stake"], (30 * TGAS).to_string());
+
+    // out of bounds
+    let mut too_much = gas_config.clone();
+    too_much["unstake"] = json!((150 * TGAS).to_string());
+    call_some_fail(&root, lockup_stake.account_id(), "set_gas_config", json!({ "gas_config": too_much }), 1);
+    let mut starved_callback = gas_config.clone();
+    starved_callback["after_unstake"] = json!(TGAS.to_string());
+    call_some_fail(&root, lockup_stake.account_id(), "set_gas_config", json!({ "gas_config": starved_callback }), 1);
+
+    gas_config["deposit_and_stake"] = json!((60 * TGAS).to_string());
+    // only the owner
+    call_some_fail(&user1, lockup_stake.account_id(), "set_gas_config", json!({ "gas_config": gas_config }), 1);
+    call(&root, lockup_stake.account_id(), "set_gas_config", json!({ "gas_config": gas_config }), 1, 0);
+
+    // the 75 TGAS the lockup contract attaches are not enough now, it fails before locking the account
+    assert_some_fail(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    assert!(view!(lockup_stake.get_account_pending_operation(user1.account_id()))
+        .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        .is_none());
+    assert_all_success(call!(user1, lockup_stake.deposit_and_stake(), 100 * NEAR, 150 * TGAS));
+}
+
+#[test]
+fn test_lockup_account_patterns() {
+    let (root, _lockupy_testnet, lockup_stake, _lockup) = setup();
+    let sandbox_lockup = root.create_user("sandbox-lockup.root".parse().unwrap(), to_yocto("100000"));
+    storage_register(&root, sandbox_lockup.account_id());
+
+    assert!(!view!(lockup_stake.is_lockup_account(sandbox_lockup.account_id())).unwrap_json::<bool>());
+    assert_some_fail(call!(sandbox_lockup, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+
+    // a suffix must start with a dot
+    call_some_fail(
+        &root,
+        lockup_stake.account_id(),
+        "set_lockup_account_patterns",
+        json!({ "patterns": { "suffixes": ["sandbox"], "exceptions": [] } }),
+        1,
+    );
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "set_lockup_account_patterns",
+        json!({ "patterns": { "suffixes": [".lockup.near"], "exceptions": ["sandbox-lockup.root"] } }),
+        1,
+        0,
+    );
+    assert!(view!(lockup_stake.is_lockup_account(sandbox_lockup.account_id())).unwrap_json::<bool>());
+    assert!(!view!(lockup_stake.is_lockup_account(lockup_account_id())).unwrap_json::<bool>());
+    assert_all_success(call!(sandbox_lockup, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+}
+
+#[test]
+fn test_allowlist() {
+    let (root, _lockupy_testnet, lockup_stake, _lockup) = setup();
+    let dao = root.create_user("dao.root".parse().unwrap(), to_yocto("100000"));
+    storage_register(&root, dao.account_id());
+    assert_some_fail(call!(dao, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+
+    // only the owner
+    call_some_fail(
+        &dao,
+        lockup_stake.account_id(),
+        "add_to_allowlist",
+        json!({ "account_id": dao.account_id() }),
+        1,
+    );
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "add_to_allowlist",
+        json!({ "account_id": dao.account_id(), "max_stake": (1000 * NEAR).to_string() }),
+        1,
+        0,
+    );
+    let allowlist: Vec<AllowlistEntry> = view!(lockup_stake.get_allowlist(0, 10)).unwrap_json();
+    assert_eq!(
+        allowlist,
+        vec![AllowlistEntry {
+            account_id: dao.account_id(),
+            max_stake: Some((1000 * NEAR).into()),
+        }]
+    );
+
+    // above the cap
+    assert_some_fail(call!(dao, lockup_stake.deposit_and_stake(), 1500 * NEAR, 75 * TGAS));
+    assert_all_success(call!(dao, lockup_stake.deposit_and_stake(), 800 * NEAR, 75 * TGAS));
+    assert_some_fail(call!(dao, lockup_stake.deposit_and_stake(), 300 * NEAR, 75 * TGAS));
+    // the deposited balance counts for the cap too
+    assert_some_fail(call!(dao, lockup_stake.deposit(), 300 * NEAR, 75 * TGAS));
+    assert_all_success(call!(dao, lockup_stake.deposit(), 150 * NEAR, 75 * TGAS));
+    assert_some_fail(call!(dao, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    // and it's checked again when staked: a lowered cap applies to the deposited balance
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "add_to_allowlist",
+        json!({ "account_id": dao.account_id(), "max_stake": (900 * NEAR).to_string() }),
+        1,
+        0,
+    );
+    assert_some_fail(call!(dao, lockup_stake.stake_all(), 0, 125 * TGAS));
+    assert_eq!(
+        to_int(view!(lockup_stake.get_account_deposited_balance(dao.account_id()))),
+        150 * NEAR
+    );
+    let balance_before = dao.account().unwrap().amount;
+    assert_all_success(call!(dao, lockup_stake.withdraw((150 * NEAR).into()), 0, 125 * TGAS));
+    assert_between(dao.account().unwrap().amount - balance_before, "149.9", "150");
+
+    // removed, it can't deposit but it can leave
+    call(
+        &root,
+        lockup_stake.account_id(),
+        "remove_from_allowlist",
+        json!({ "account_id": dao.account_id() }),
+        1,
+        0,
+    );
+    assert_eq!(view!(lockup_stake.get_allowlist_len()).unwrap_json::<u64>(), 0);
+    assert_some_fail(call!(dao, lockup_stake.deposit_and_stake(), 100 * NEAR, 75 * TGAS));
+    assert_all_success(call!(dao, lockup_stake.unstake_all(), 0, 125 * TGAS));
+}
+
+#[test]
+fn test_verify_lockup_accounts() {
+    let (root, lockupy_testnet, lockup_stake, lockup) = setup();
+    call(&root, lockup_stake.account_id(), "set_verify_lockup_accounts", json!({ "enabled": true }), 1, 0);
+
+    // named like a lockup, but it's not a lockup contract
+    let fake = lockupy_testnet.create_user("fake.lockupy.testnet".parse().unwrap(), to_yocto("100000"));
+    storage_register(&lockupy_testnet, fake.account_id());
+    let balance_before = fake.account().unwrap().amount;
+    let result = call!(fake, lockup_stake.deposit_and_stake(), 1000 * NEAR, 75 * TGAS);
+    assert!(events(&result)
+        .iter()
+        .any(|event| event["event"] == "lockup_verification_failed"));
+    assert_some_fail(result);
+    // refunded (minus gas) and not busy
+    assert_tolerance(fake.account().unwrap().amount, balance_before, 10);
+    assert!(view!(lockup_stake.get_account_pending_operation(fake.account_id()))
+        .unwrap_json::<Option<HumanReadablePendingOperation>>()
+        .is_none());
+    assert!(!view!(lockup_stake.is_lockup_verified(fake.account_id())).unwrap_json::<bool>());
+
+    // a lockup with this contract as staking pool
+    storage_register(&root, lockup_account_id());
+    call(
+        &root,
+        lockup_account_id(),
+        "select_staking_pool",
+        json!({ "staking_pool_account_id": LOCKUP_STAKE_CONTRACT_ID }),
+        0,
+        0,
+    );
+    call(
+        &root,
+        lockup_account_id(),
+        "deposit_and_stake",
+        json!({ "amount": (1000 * NEAR).to_string() }),
+        0,
+        125 * TGAS,
+    );
+    assert!(view!(lockup_stake.is_lockup_verified(lockup.account_id())).unwrap_json::<bool>());
+    assert_between(
+        to_int(view!(lockup_stake.get_account_staked_balance(lockup.account_id()))),
+        "999.99",
+        "1000.01",
+    );
+}
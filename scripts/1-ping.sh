@@ -1,2 +1,2 @@
 . scripts/.vars.sh
-near call $CONTRACT_ACC ping --accountId $OWNER
+near call $CONTRACT_ACC ping --accountId $OWNER --gas 50000000000000
@@ -16,5 +16,10 @@ set -ex
 # near deploy $CONTRACT_ACC $WASM \
 #      new "{\"owner_id\":\"$OWNER\", \"meta_pool_contract_id\":\"$META_POOL_CONTRACT\"}"
 
+# RE-DEPLOY, code + state migration
+# near deploy $CONTRACT_ACC $WASM migrate "{}"
+# then, until it returns 0 (via DAO proposal)
+# near call $CONTRACT_ACC migrate_accounts "{\"limit\":100}" --accountId $OWNER
+
 # first ping
 near call $CONTRACT_ACC ping --accountId $CONTRACT_ACC --gas 50000000000000
\ No newline at end of file